@@ -1,20 +1,62 @@
-use bump::BumpAllocator;
+use core::{
+    alloc::Layout,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
+#[cfg(feature = "alloc-bump")]
+use bump::BumpAllocator;
+#[cfg(feature = "alloc-external")]
+use external::ExternalAllocator;
+#[cfg(feature = "alloc-fixed-block")]
 use fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-linked-list")]
 use linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+use magazine::Magazines;
+
+use crate::{
+    memory::{protect, vmm},
+    serial_println,
+};
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
     },
     VirtAddr,
 };
 
 pub mod bump;
+pub mod debug;
+pub mod external;
 pub mod fixed_size_block;
 pub mod linked_list;
+pub mod magazine;
+pub mod slab;
+mod stats;
+pub mod try_alloc;
+
+pub use stats::{
+    dump_live_allocations, live_allocations, set_leak_tracking, stats, HeapStats, LiveAllocation,
+    Tracked,
+};
+pub use try_alloc::{try_box, try_vec_with_capacity, AllocError, TryClone};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Size of the virtual region reserved for the heap, the ceiling for
+/// `set_heap_limit`
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// Minimum number of bytes mapped at once when the heap grows
+const HEAP_GROW_STEP: usize = 64 * 1024; // 64 KiB
+
+/// Current end of the mapped heap
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+/// Maximum size the heap may grow to
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
 
 /// A wrapper around a `spin::Mutex` to permit trait implementations
 pub struct Locked<T> {
@@ -28,17 +70,138 @@ impl<T> Locked<T> {
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<T> {
-        self.inner.lock()
+    /// Lock the allocator, keeping interrupts disabled until the guard is
+    /// dropped, so that interrupt handlers can allocate without deadlocking.
+    pub fn lock(&self) -> LockedGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        LockedGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<LockedGuard<'_, T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(LockedGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_enabled,
+            }),
+            None => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// The guard of `Locked`, which restores the interrupt flag on drop
+pub struct LockedGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T> Deref for LockedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for LockedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
     }
 }
 
+impl<T> Drop for LockedGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock before interrupts can come in again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+// The global allocator is selected by exactly one of the `alloc-*` features
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block",
+    feature = "alloc-external"
+)))]
+compile_error!("no allocator backend selected, enable one of the `alloc-*` features");
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-bump", feature = "alloc-external"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-external"),
+    all(feature = "alloc-fixed-block", feature = "alloc-external")
+))]
+compile_error!("multiple allocator backends selected, use `--no-default-features`");
+
+/// Name of the allocator backend selected by the `alloc-*` features
+pub const BACKEND: &str = if cfg!(feature = "alloc-bump") {
+    "bump"
+} else if cfg!(feature = "alloc-linked-list") {
+    "linked-list"
+} else if cfg!(feature = "alloc-fixed-block") {
+    "fixed-block"
+} else {
+    "external"
+};
+
+/// The checks of the `alloc-debug` feature, wrapped around the backend
+#[cfg(feature = "alloc-debug")]
+type Checked<A> = debug::Guarded<A>;
+#[cfg(not(feature = "alloc-debug"))]
+type Checked<A> = A;
+
+#[cfg(feature = "alloc-debug")]
+const fn checked<A>(inner: A) -> Checked<A> {
+    debug::Guarded::new(inner)
+}
+
+#[cfg(not(feature = "alloc-debug"))]
+const fn checked<A>(inner: A) -> Checked<A> {
+    inner
+}
+
+#[cfg(feature = "alloc-bump")]
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static ALLOCATOR: Tracked<Checked<Locked<BumpAllocator>>> =
+    Tracked::new(checked(Locked::new(BumpAllocator::new())));
+
+#[cfg(feature = "alloc-linked-list")]
+#[global_allocator]
+static ALLOCATOR: Tracked<Checked<Locked<LinkedListAllocator>>> =
+    Tracked::new(checked(Locked::new(LinkedListAllocator::new())));
+
+/// Only the fixed-size block backend gets the magazine front-end: its size
+/// classes match the magazines, while the other backends would pay for the
+/// rounding with alignment padding or, for bump, blocks that never free.
+#[cfg(feature = "alloc-fixed-block")]
+#[global_allocator]
+static ALLOCATOR: Tracked<Checked<Magazines<Locked<FixedSizeBlockAllocator>>>> = Tracked::new(
+    checked(Magazines::new(Locked::new(FixedSizeBlockAllocator::new()))),
+);
+
+#[cfg(feature = "alloc-external")]
+#[global_allocator]
+static ALLOCATOR: Tracked<Checked<Locked<ExternalAllocator>>> =
+    Tracked::new(checked(Locked::new(ExternalAllocator::new())));
 
 pub unsafe fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
 ) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
@@ -48,23 +211,126 @@ pub unsafe fn init_heap(
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
+    vmm::reserve(
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_MAX_SIZE as u64,
+        vmm::RegionKind::Heap,
+    )
+    .expect("heap region overlaps another region");
+
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            // flush TLB
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-        }
+        map_heap_page(page, mapper, frame_allocator)?;
     }
+    HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::Relaxed);
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
     }
     Ok(())
 }
 
+/// Map a single heap page to a newly allocated frame.
+///
+/// The frame is returned to `frame_allocator` if the mapping fails.
+fn map_heap_page(
+    page: Page,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protect::no_execute();
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        // flush TLB
+        Ok(flush) => flush.flush(),
+        Err(err) => {
+            // e.g. no frame was left for a page table
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Set the maximum size the heap may grow to.
+///
+/// The limit is clamped between the currently mapped heap size and
+/// `HEAP_MAX_SIZE`.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.clamp(heap_size(), HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+/// Returns the number of bytes currently mapped for the heap.
+pub fn heap_size() -> usize {
+    HEAP_END.load(Ordering::Relaxed) - HEAP_START
+}
+
+/// Map at least `min_size` more bytes directly after the current heap end.
+///
+/// Called by the allocators when they run out of memory, so it must not
+/// allocate itself. Returns the number of bytes added to the heap, which may
+/// be less than requested if frames run out, or `None` if the heap limit is
+/// reached or nothing could be mapped.
+fn grow_heap(min_size: usize) -> Option<usize> {
+    let heap_end = HEAP_END.load(Ordering::Relaxed);
+    let available = (HEAP_START + HEAP_LIMIT.load(Ordering::Relaxed)).saturating_sub(heap_end);
+    let size = align_up(min_size.max(HEAP_GROW_STEP), 4096).min(available);
+    if size < min_size {
+        return None;
+    }
+
+    let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(heap_end as u64));
+    let mapped = crate::memory::try_with_kernel_paging(|mapper, frame_allocator| {
+        (0..(size / 4096) as u64)
+            .take_while(|&i| map_heap_page(start_page + i, mapper, frame_allocator).is_ok())
+            .count()
+            * 4096
+    })?;
+    HEAP_END.store(heap_end + mapped, Ordering::Relaxed);
+    (mapped > 0).then_some(mapped)
+}
+
+/// Report a failed allocation with the heap state on the serial port and
+/// panic.
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    serial_println!("allocation error: {:?}", layout);
+    serial_println!("{}", stats());
+    match ALLOCATOR.try_lock() {
+        Some(allocator) => allocator.print_free_lists(),
+        None => serial_println!("allocator locked, free lists unavailable"),
+    }
+    panic!("allocation error: {:?}", layout)
+}
+
+/// Returns the number of bytes used in the fallback heap of the fixed size
+/// block allocator.
+#[cfg(feature = "alloc-fixed-block")]
+fn fallback_bytes() -> Option<usize> {
+    Some(ALLOCATOR.lock().fallback_used())
+}
+
+#[cfg(not(feature = "alloc-fixed-block"))]
+fn fallback_bytes() -> Option<usize> {
+    None
+}
+
 /// Align the given address upwards to the nearest multiple of `align`.
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
+
+/// Returns the start of a page aligned static heap of `size` bytes for the
+/// allocator unit tests.
+///
+/// All callers share the same memory, so only one test may use it at a time.
+#[cfg(test)]
+fn test_heap(size: usize) -> usize {
+    const TEST_HEAP_CAPACITY: usize = 64 * 1024;
+    #[repr(align(4096))]
+    struct TestHeap([u8; TEST_HEAP_CAPACITY]);
+    static mut TEST_HEAP: TestHeap = TestHeap([0; TEST_HEAP_CAPACITY]);
+
+    assert!(size <= TEST_HEAP_CAPACITY, "test heap too large");
+    unsafe { core::ptr::addr_of_mut!(TEST_HEAP.0) as usize }
+}
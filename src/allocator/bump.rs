@@ -1,6 +1,7 @@
 use core::{alloc::GlobalAlloc, ptr};
 
 use super::{align_up, Locked};
+use crate::serial_println;
 
 pub struct BumpAllocator {
     heap_start: usize,
@@ -19,6 +20,22 @@ impl BumpAllocator {
         }
     }
 
+    /// Returns the number of live allocations.
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    /// Print the allocator state to the serial port.
+    pub fn print_free_lists(&self) {
+        serial_println!(
+            "bump: next {:#x}, end {:#x}, {} bytes left, {} allocations",
+            self.next,
+            self.heap_end,
+            self.heap_end - self.next,
+            self.allocations
+        );
+    }
+
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.heap_start = heap_start;
         self.heap_end = heap_start + heap_size;
@@ -35,6 +52,12 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             None => return ptr::null_mut(),
         };
 
+        if end > bump.heap_end {
+            // the heap is exhausted: map more pages after its end
+            if let Some(added) = super::grow_heap(end - bump.heap_end) {
+                bump.heap_end += added;
+            }
+        }
         if end > bump.heap_end {
             ptr::null_mut()
         } else {
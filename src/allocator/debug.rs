@@ -0,0 +1,248 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt, mem,
+    ops::Deref,
+};
+
+use super::align_up;
+
+/// Bytes left unused at the start of every block, so that the free list
+/// node the backend writes into a freed block does not overwrite the header
+const NODE_SPACE: usize = 16;
+/// Size of the red zones before and after every block
+const RED_ZONE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xfd;
+/// Byte pattern of freed memory
+const POISON_FREE: u8 = 0x6b;
+const MAGIC_ALLOCATED: u64 = 0xa110_ca7e_d0b1_0c55;
+const MAGIC_FREED: u64 = 0xf7ee_d0b1_0c55_dead;
+
+/// Stored in front of the red zone before every block
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+}
+
+/// A heap error detected when a block is freed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// The block was already freed
+    DoubleFree { addr: usize, size: usize },
+    /// The header does not belong to an allocated block
+    NotAllocated { addr: usize, size: usize },
+    /// The block is freed with another layout than it was allocated with
+    LayoutMismatch {
+        addr: usize,
+        size: usize,
+        align: usize,
+        freed_size: usize,
+        freed_align: usize,
+    },
+    /// A red zone was overwritten, `offset` is relative to the block start
+    RedZone {
+        addr: usize,
+        size: usize,
+        offset: isize,
+    },
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Corruption::DoubleFree { addr, size } => {
+                write!(f, "double free of {:#x} ({} bytes)", addr, size)
+            }
+            Corruption::NotAllocated { addr, size } => write!(
+                f,
+                "free of {:#x} ({} bytes), which is not an allocated block",
+                addr, size
+            ),
+            Corruption::LayoutMismatch {
+                addr,
+                size,
+                align,
+                freed_size,
+                freed_align,
+            } => write!(
+                f,
+                "{:#x} allocated with size {} align {}, freed with size {} align {}",
+                addr, size, align, freed_size, freed_align
+            ),
+            Corruption::RedZone { addr, size, offset } => write!(
+                f,
+                "red zone of {:#x} ({} bytes) overwritten at offset {}",
+                addr, size, offset
+            ),
+        }
+    }
+}
+
+/// A wrapper around an allocator that surrounds every block with red zones,
+/// poisons freed blocks and checks every `dealloc`.
+///
+/// Detected errors panic with the address and size of the block.
+pub struct Guarded<A> {
+    inner: A,
+}
+
+impl<A> Guarded<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+
+    /// Returns the layout of the surrounding block and the offset of the
+    /// user block in it.
+    fn outer_layout(layout: Layout) -> (Layout, usize) {
+        let align = layout.align().max(mem::align_of::<Header>());
+        let offset = align_up(NODE_SPACE + mem::size_of::<Header>() + RED_ZONE, align);
+        let outer = Layout::from_size_align(offset + layout.size() + RED_ZONE, align)
+            .expect("guarded layout overflow");
+        (outer, offset)
+    }
+
+    fn header(ptr: *mut u8) -> *mut Header {
+        (ptr as usize - RED_ZONE - mem::size_of::<Header>()) as *mut Header
+    }
+
+    /// Check the block at `ptr` before it is freed with `layout`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` of this allocator.
+    pub unsafe fn check(&self, ptr: *mut u8, layout: Layout) -> Result<(), Corruption> {
+        let header = &*Self::header(ptr);
+        let (addr, size) = (ptr as usize, layout.size());
+        match header.magic {
+            MAGIC_ALLOCATED => {}
+            MAGIC_FREED => return Err(Corruption::DoubleFree { addr, size }),
+            _ => return Err(Corruption::NotAllocated { addr, size }),
+        }
+        if header.size != layout.size() || header.align != layout.align() {
+            return Err(Corruption::LayoutMismatch {
+                addr,
+                size: header.size,
+                align: header.align,
+                freed_size: layout.size(),
+                freed_align: layout.align(),
+            });
+        }
+        let before = core::slice::from_raw_parts(ptr.sub(RED_ZONE), RED_ZONE);
+        if let Some(i) = before.iter().position(|&b| b != RED_ZONE_BYTE) {
+            let offset = i as isize - RED_ZONE as isize;
+            return Err(Corruption::RedZone { addr, size, offset });
+        }
+        let after = core::slice::from_raw_parts(ptr.add(size), RED_ZONE);
+        if let Some(i) = after.iter().position(|&b| b != RED_ZONE_BYTE) {
+            let offset = (size + i) as isize;
+            return Err(Corruption::RedZone { addr, size, offset });
+        }
+        Ok(())
+    }
+}
+
+impl<A> Deref for Guarded<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Guarded<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, offset) = Self::outer_layout(layout);
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+        let block = base.add(offset);
+        Self::header(block).write(Header {
+            magic: MAGIC_ALLOCATED,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        block.sub(RED_ZONE).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        block
+            .add(layout.size())
+            .write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        block
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(corruption) = self.check(ptr, layout) {
+            panic!("heap corruption: {}", corruption);
+        }
+        let (outer, offset) = Self::outer_layout(layout);
+        (*Self::header(ptr)).magic = MAGIC_FREED;
+        ptr.write_bytes(POISON_FREE, layout.size());
+        self.inner.dealloc(ptr.sub(offset), outer);
+    }
+}
+
+#[cfg(test)]
+fn with_test_allocator(
+    f: impl FnOnce(&Guarded<super::Locked<super::linked_list::LinkedListAllocator>>),
+) {
+    use super::{linked_list::LinkedListAllocator, Locked};
+
+    const TEST_HEAP_SIZE: usize = 4096;
+
+    let allocator = Guarded::new(Locked::new(LinkedListAllocator::new()));
+    unsafe {
+        allocator
+            .lock()
+            .init(super::test_heap(TEST_HEAP_SIZE), TEST_HEAP_SIZE)
+    };
+    f(&allocator);
+}
+
+#[test_case]
+fn test_detects_red_zone_overwrite() {
+    with_test_allocator(|allocator| unsafe {
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let block = allocator.alloc(layout);
+        block.write_bytes(0, 24);
+        assert_eq!(allocator.check(block, layout), Ok(()));
+        block.add(24).write(0);
+        assert_eq!(
+            allocator.check(block, layout),
+            Err(Corruption::RedZone {
+                addr: block as usize,
+                size: 24,
+                offset: 24
+            })
+        );
+        block.sub(1).write(0);
+        assert!(matches!(
+            allocator.check(block, layout),
+            Err(Corruption::RedZone { offset: -1, .. })
+        ));
+    });
+}
+
+#[test_case]
+fn test_detects_double_free_and_layout_mismatch() {
+    with_test_allocator(|allocator| unsafe {
+        let layout = Layout::from_size_align(64, 16).unwrap();
+        let block = allocator.alloc(layout);
+        let wrong = Layout::from_size_align(32, 16).unwrap();
+        assert!(matches!(
+            allocator.check(block, wrong),
+            Err(Corruption::LayoutMismatch {
+                size: 64,
+                freed_size: 32,
+                ..
+            })
+        ));
+        allocator.dealloc(block, layout);
+        assert!(block.read() == POISON_FREE);
+        assert_eq!(
+            allocator.check(block, layout),
+            Err(Corruption::DoubleFree {
+                addr: block as usize,
+                size: 64
+            })
+        );
+    });
+}
@@ -0,0 +1,71 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+
+use super::Locked;
+use crate::serial_println;
+
+/// The heap of the `linked_list_allocator` crate, extended with heap growth
+pub struct ExternalAllocator {
+    heap: linked_list_allocator::Heap,
+}
+
+impl ExternalAllocator {
+    pub const fn new() -> Self {
+        Self {
+            heap: linked_list_allocator::Heap::empty(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// The memory in `heap_start..heap_start + heap_size` must be mapped,
+    /// unused and valid for the lifetime of the allocator. This method must
+    /// be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start as *mut u8, heap_size);
+    }
+
+    /// Print the heap usage to the serial port.
+    pub fn print_free_lists(&self) {
+        serial_println!(
+            "external: {} bytes used, {} bytes free",
+            self.heap.used(),
+            self.heap.free()
+        );
+    }
+}
+
+impl Default for ExternalAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<ExternalAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        if let Ok(p) = allocator.heap.allocate_first_fit(layout) {
+            return p.as_ptr();
+        }
+        // the heap is exhausted: map more pages after its end and retry
+        match super::grow_heap(layout.size() + layout.align()) {
+            Some(added) => {
+                allocator.heap.extend(added);
+                match allocator.heap.allocate_first_fit(layout) {
+                    Ok(p) => p.as_ptr(),
+                    Err(_) => ptr::null_mut(),
+                }
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new(ptr).unwrap();
+        self.lock().heap.deallocate(ptr, layout);
+    }
+}
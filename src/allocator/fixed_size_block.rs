@@ -1,26 +1,59 @@
 use core::{
     alloc::{GlobalAlloc, Layout},
+    mem,
     ptr::{self, NonNull},
 };
 
-use super::Locked;
+use super::{align_up, Locked};
+use crate::serial_println;
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub(super) const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// Size classes below this index are refilled a slab at a time, larger
+/// blocks are allocated from the fallback one by one
+const SLAB_CLASSES: usize = 7;
+/// Size and alignment of a slab
+const SLAB_SIZE: usize = 4096;
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
+/// Header at the start of every slab
+struct Slab {
+    /// Number of blocks of this slab that are in the free list
+    free: usize,
+    next: Option<&'static mut Slab>,
+}
+
+/// Offset of the first block of a slab of size class `idx`
+fn first_block_offset(idx: usize) -> usize {
+    align_up(mem::size_of::<Slab>(), BLOCK_SIZES[idx])
+}
+
+/// Number of blocks of a slab of size class `idx`
+fn slab_capacity(idx: usize) -> usize {
+    (SLAB_SIZE - first_block_offset(idx)) / BLOCK_SIZES[idx]
+}
+
+/// Returns the header of the slab containing the block at `addr`.
+fn slab_of(addr: usize) -> &'static mut Slab {
+    unsafe { &mut *((addr & !(SLAB_SIZE - 1)) as *mut Slab) }
+}
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    /// The slabs of every size class below `SLAB_CLASSES`
+    slabs: [Option<&'static mut Slab>; SLAB_CLASSES],
     fallback_allocator: linked_list_allocator::Heap,
 }
 
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
         const EMPTY: Option<&'static mut ListNode> = None;
+        const NO_SLAB: Option<&'static mut Slab> = None;
         Self {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
+            slabs: [NO_SLAB; SLAB_CLASSES],
             fallback_allocator: linked_list_allocator::Heap::empty(),
         }
     }
@@ -31,55 +64,237 @@ impl FixedSizeBlockAllocator {
     }
 
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(p) => p.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        if let Ok(p) = self.fallback_allocator.allocate_first_fit(layout) {
+            return p.as_ptr();
+        }
+        // under memory pressure: give cached blocks back and retry
+        if self.reclaim() > 0 {
+            if let Ok(p) = self.fallback_allocator.allocate_first_fit(layout) {
+                return p.as_ptr();
+            }
+        }
+        // the heap is exhausted: map more pages after its end and retry
+        match super::grow_heap(layout.size() + layout.align()) {
+            Some(added) => unsafe {
+                self.fallback_allocator.extend(added);
+                match self.fallback_allocator.allocate_first_fit(layout) {
+                    Ok(p) => p.as_ptr(),
+                    Err(_) => ptr::null_mut(),
+                }
+            },
+            None => ptr::null_mut(),
         }
     }
-}
 
-unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
+    /// Returns the number of bytes allocated from the fallback allocator,
+    /// including slabs and cached blocks.
+    pub fn fallback_used(&self) -> usize {
+        self.fallback_allocator.used()
+    }
+
+    /// Print the number of free blocks per size class and the fallback heap
+    /// usage to the serial port.
+    pub fn print_free_lists(&self) {
+        for (idx, head) in self.list_heads.iter().enumerate() {
+            let free = core::iter::successors(head.as_deref(), |node| node.next.as_deref()).count();
+            let slabs = self.slabs.get(idx).map_or(0, |slabs| {
+                core::iter::successors(slabs.as_deref(), |slab| slab.next.as_deref()).count()
+            });
+            serial_println!(
+                "  {:>4} B: {} free blocks, {} slabs",
+                BLOCK_SIZES[idx],
+                free,
+                slabs
+            );
+        }
+        serial_println!(
+            "  fallback: {} bytes used, {} bytes free",
+            self.fallback_allocator.used(),
+            self.fallback_allocator.free()
+        );
+    }
+
+    /// Carve a new slab into blocks of size class `idx` and add them to the
+    /// free list.
+    fn refill(&mut self, idx: usize) -> bool {
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        let slab_start = self.fallback_alloc(layout) as usize;
+        if slab_start == 0 {
+            return false;
+        }
+        let block_size = BLOCK_SIZES[idx];
+        for i in 0..slab_capacity(idx) {
+            let block = slab_start + first_block_offset(idx) + i * block_size;
+            self.push_block(idx, block);
+        }
+        let slab = Slab {
+            free: slab_capacity(idx),
+            next: self.slabs[idx].take(),
+        };
+        unsafe {
+            let slab_ptr = slab_start as *mut Slab;
+            slab_ptr.write(slab);
+            self.slabs[idx] = Some(&mut *slab_ptr);
+        }
+        true
+    }
+
+    fn push_block(&mut self, idx: usize, addr: usize) {
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[idx]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[idx]);
+        let new_node = ListNode {
+            next: self.list_heads[idx].take(),
+        };
+        unsafe {
+            let new_node_ptr = addr as *mut ListNode;
+            new_node_ptr.write(new_node);
+            self.list_heads[idx] = Some(&mut *new_node_ptr);
+        }
+    }
+
+    /// Return fully free slabs and cached blocks larger than the slab
+    /// classes to the fallback allocator.
+    ///
+    /// Returns the number of bytes released.
+    pub fn reclaim(&mut self) -> usize {
+        let mut released = 0;
+        for (idx, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            let capacity = (idx < SLAB_CLASSES).then(|| slab_capacity(idx));
+            let releasable = |addr: usize| capacity.is_none_or(|c| slab_of(addr).free == c);
+
+            // drop the blocks that are released with their slab
+            let mut list = self.list_heads[idx].take();
+            while let Some(node) = list {
+                list = node.next.take();
+                let addr = node as *mut ListNode as usize;
+                if !releasable(addr) {
+                    self.push_block(idx, addr);
+                } else if capacity.is_none() {
+                    let layout = Layout::from_size_align(block_size, block_size);
+                    unsafe {
+                        self.fallback_allocator
+                            .deallocate(NonNull::new_unchecked(addr as *mut u8), layout.unwrap());
+                    }
+                    released += block_size;
+                }
+            }
+
+            if idx < SLAB_CLASSES {
+                let mut slabs = self.slabs[idx].take();
+                while let Some(slab) = slabs {
+                    slabs = slab.next.take();
+                    let addr = slab as *mut Slab as usize;
+                    if releasable(addr) {
+                        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+                        unsafe {
+                            self.fallback_allocator
+                                .deallocate(NonNull::new_unchecked(addr as *mut u8), layout);
+                        }
+                        released += SLAB_SIZE;
+                    } else {
+                        slab.next = self.slabs[idx].take();
+                        self.slabs[idx] = Some(slab);
+                    }
+                }
+            }
+        }
+        released
+    }
+
+    /// Allocate a block for `layout`, returns null if memory is exhausted.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
         match list_index(&layout) {
-            Some(idx) => match allocator.list_heads[idx].take() {
+            Some(idx) if idx < SLAB_CLASSES => {
+                if self.list_heads[idx].is_none() && !self.refill(idx) {
+                    return ptr::null_mut();
+                }
+                let node = self.list_heads[idx].take().unwrap();
+                self.list_heads[idx] = node.next.take();
+                let addr = node as *mut ListNode as usize;
+                slab_of(addr).free -= 1;
+                addr as *mut u8
+            }
+            Some(idx) => match self.list_heads[idx].take() {
                 Some(node) => {
-                    allocator.list_heads[idx] = node.next.take();
+                    self.list_heads[idx] = node.next.take();
                     node as *mut ListNode as *mut u8
                 }
                 None => {
                     let block_size = BLOCK_SIZES[idx];
                     let block_align = block_size;
                     let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                    allocator.fallback_alloc(layout)
+                    self.fallback_alloc(layout)
                 }
             },
-            None => allocator.fallback_alloc(layout),
+            None => self.fallback_alloc(layout),
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
+    /// Free a block returned by `allocate`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `allocate` with the same layout.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
         match list_index(&layout) {
             Some(idx) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[idx].take(),
-                };
-                assert!(core::mem::size_of::<ListNode>() <= BLOCK_SIZES[idx]);
-                assert!(core::mem::align_of::<ListNode>() <= BLOCK_SIZES[idx]);
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[idx] = Some(&mut *new_node_ptr);
+                if idx < SLAB_CLASSES {
+                    slab_of(ptr as usize).free += 1;
+                }
+                self.push_block(idx, ptr as usize);
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
+                self.fallback_allocator.deallocate(ptr, layout);
             }
         }
     }
 }
 
-fn list_index(layout: &Layout) -> Option<usize> {
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout);
+    }
+}
+
+pub(super) fn list_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
+
+#[test_case]
+fn test_reclaim_returns_free_slabs() {
+    const TEST_HEAP_SIZE: usize = 64 * 1024;
+
+    let mut allocator = FixedSizeBlockAllocator::new();
+    unsafe { allocator.init(super::test_heap(TEST_HEAP_SIZE), TEST_HEAP_SIZE) };
+    let free_before = allocator.fallback_allocator.free();
+
+    // a burst of small allocations fills several slabs
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let large = Layout::from_size_align(2048, 8).unwrap();
+    let mut blocks = [ptr::null_mut(); 200];
+    for block in blocks.iter_mut() {
+        *block = allocator.allocate(small);
+        assert!(!block.is_null());
+    }
+    let kept = allocator.allocate(small);
+    let large_block = allocator.allocate(large);
+    assert_eq!(slab_of(blocks[0] as usize).free, 0);
+    for block in blocks {
+        unsafe { allocator.deallocate(block, small) };
+    }
+    unsafe { allocator.deallocate(large_block, large) };
+
+    // everything but the slab of `kept` goes back to the fallback
+    assert!(allocator.reclaim() > 0);
+    assert_eq!(allocator.fallback_allocator.free(), free_before - SLAB_SIZE);
+    assert_eq!(slab_of(kept as usize).free, slab_capacity(3) - 1);
+    unsafe { allocator.deallocate(kept, small) };
+    allocator.reclaim();
+    assert_eq!(allocator.fallback_allocator.free(), free_before);
+}
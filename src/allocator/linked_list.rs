@@ -6,36 +6,104 @@ use core::{
 use crate::allocator::align_up;
 
 use super::Locked;
+use crate::serial_println;
 
-
-
+/// How `find_region` picks the free region for an allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    /// The region with the lowest address that fits
+    FirstFit,
+    /// The smallest region that fits, which keeps large regions intact
+    BestFit,
+}
 
 pub struct LinkedListAllocator {
+    /// Dummy head of the free list, which is sorted by address
     head: ListNode,
+    strategy: FitStrategy,
 }
 
 impl LinkedListAllocator {
     pub const fn new() -> Self {
+        Self::with_strategy(FitStrategy::FirstFit)
+    }
+
+    pub const fn with_strategy(strategy: FitStrategy) -> Self {
         Self {
             head: ListNode::new(0),
+            strategy,
         }
     }
 
+    pub fn set_strategy(&mut self, strategy: FitStrategy) {
+        self.strategy = strategy;
+    }
+
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
     }
 
-    /// Add a new free region to the front of the list.
+    /// Returns the number of regions in the free list.
+    pub fn free_regions(&self) -> usize {
+        self.regions().count()
+    }
+
+    /// Returns the total size of all free regions.
+    pub fn free_bytes(&self) -> usize {
+        self.regions().map(|region| region.size).sum()
+    }
+
+    /// Print the free regions to the serial port.
+    pub fn print_free_lists(&self) {
+        serial_println!(
+            "linked list ({:?}): {} bytes free in {} regions",
+            self.strategy,
+            self.free_bytes(),
+            self.free_regions()
+        );
+        for region in self.regions() {
+            serial_println!("  {:#x}: {} bytes", region.start_addr(), region.size);
+        }
+    }
+
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        core::iter::successors(self.head.next.as_deref(), |region| region.next.as_deref())
+    }
+
+    /// Insert a free region into the list, merging it with the regions
+    /// directly before and after it.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure that the region is capable of holding a ListNode
         assert_eq!(align_up(addr, core::mem::size_of::<ListNode>()), addr);
         assert!(size >= core::mem::size_of::<ListNode>());
+
+        // find the last region before the new one
+        let head = &self.head as *const ListNode;
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        // write the node to the start of the region
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr);
+        node.next = current.next.take();
+        if let Some(next) = node.next.take_if(|next| next.start_addr() == addr + size) {
+            node.size += next.size;
+            node.next = next.next.take();
+        }
+
+        if !ptr::eq(current, head) && current.end_addr() == addr {
+            current.size += node.size;
+            current.next = node.next;
+        } else {
+            // write the node to the start of the region
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+        }
     }
 
     /// Try to allocate memory from the given region.
@@ -71,44 +139,80 @@ impl LinkedListAllocator {
         (size, layout.align())
     }
 
+    /// Remove a region that fits the allocation from the list, chosen by the
+    /// fit strategy.
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let fits = |region: &&ListNode| Self::alloc_from_region(region, size, align).is_ok();
+        let target = match self.strategy {
+            FitStrategy::FirstFit => self.regions().find(fits)?,
+            FitStrategy::BestFit => self.regions().filter(fits).min_by_key(|r| r.size)?,
+        }
+        .start_addr();
+
         let mut current = &mut self.head;
-        // look for a region that is large enough
-        while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
-                let next = region.next.take();
-                let ret = Some((current.next.take().unwrap(), alloc_start));
-                current.next = next;
-                return ret;
-            } else {
-                current = current.next.as_mut().unwrap();
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() != target)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+        let region = current.next.take().unwrap();
+        current.next = region.next.take();
+        let alloc_start = Self::alloc_from_region(region, size, align).unwrap();
+        Some((region, alloc_start))
+    }
+
+    /// Allocate a block for `layout` from the free regions.
+    ///
+    /// Returns the start address, or `None` if no region is large enough.
+    pub fn allocate(&mut self, layout: Layout) -> Option<usize> {
+        let (size, align) = Self::size_align(layout);
+        let (region, alloc_start) = self.find_region(size, align)?;
+        let (region_start, region_end) = (region.start_addr(), region.end_addr());
+        let alloc_end = alloc_start.checked_add(size).expect("overflow");
+        unsafe {
+            // return the alignment padding and the rest of the region
+            if alloc_start > region_start {
+                self.add_free_region(region_start, alloc_start - region_start);
+            }
+            if region_end > alloc_end {
+                self.add_free_region(alloc_end, region_end - alloc_end);
             }
         }
-        None
+        Some(alloc_start)
+    }
+
+    /// Return a block to the free regions.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must have been returned by `allocate` with the same layout.
+    pub unsafe fn deallocate(&mut self, addr: usize, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(addr, size);
     }
 }
 
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        // perform layout adjustments
-        let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
+        let mut block = allocator.allocate(layout);
+        if block.is_none() {
+            // the heap is exhausted: map more pages after its end and retry
+            let (size, align) = LinkedListAllocator::size_align(layout);
+            let heap_end = super::HEAP_START + super::heap_size();
+            if let Some(added) = super::grow_heap(size + align) {
+                allocator.add_free_region(heap_end, added);
+                block = allocator.allocate(layout);
             }
-            alloc_start as *mut u8
-        } else {
-            ptr::null_mut()
         }
+        block.map_or(ptr::null_mut(), |addr| addr as *mut u8)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let (size, _) = LinkedListAllocator::size_align(layout);
-        self.lock().add_free_region(ptr as usize, size);
+        self.lock().deallocate(ptr as usize, layout);
     }
 }
 
@@ -130,3 +234,64 @@ impl ListNode {
         self.start_addr() + self.size
     }
 }
+
+#[cfg(test)]
+const TEST_HEAP_SIZE: usize = 16 * 1024;
+
+/// Run `f` with an allocator that manages a static test heap.
+#[cfg(test)]
+fn with_test_heap(strategy: FitStrategy, f: impl FnOnce(&mut LinkedListAllocator)) {
+    let mut allocator = LinkedListAllocator::with_strategy(strategy);
+    unsafe {
+        allocator.init(super::test_heap(TEST_HEAP_SIZE), TEST_HEAP_SIZE);
+    }
+    f(&mut allocator);
+}
+
+#[test_case]
+fn test_freed_heap_is_single_region() {
+    with_test_heap(FitStrategy::FirstFit, |allocator| {
+        let layouts = [
+            Layout::from_size_align(24, 8).unwrap(),
+            Layout::from_size_align(300, 256).unwrap(),
+            Layout::from_size_align(1000, 16).unwrap(),
+            Layout::from_size_align(8, 8).unwrap(),
+            Layout::from_size_align(100, 64).unwrap(),
+        ];
+        let mut blocks = [0; 5];
+        for (block, layout) in blocks.iter_mut().zip(layouts) {
+            *block = allocator.allocate(layout).unwrap();
+        }
+        // free in an order that leaves holes before merging them
+        for i in [1, 3, 0, 4, 2] {
+            unsafe { allocator.deallocate(blocks[i], layouts[i]) };
+        }
+        assert_eq!(allocator.free_regions(), 1);
+        assert_eq!(allocator.free_bytes(), TEST_HEAP_SIZE);
+    });
+}
+
+#[test_case]
+fn test_best_fit_picks_smallest_region() {
+    for strategy in [FitStrategy::FirstFit, FitStrategy::BestFit] {
+        with_test_heap(strategy, |allocator| {
+            let large = Layout::from_size_align(512, 16).unwrap();
+            let small = Layout::from_size_align(128, 16).unwrap();
+            let separator = Layout::from_size_align(16, 16).unwrap();
+            let a = allocator.allocate(large).unwrap();
+            allocator.allocate(separator).unwrap();
+            let b = allocator.allocate(small).unwrap();
+            allocator.allocate(separator).unwrap();
+            unsafe {
+                allocator.deallocate(a, large);
+                allocator.deallocate(b, small);
+            }
+
+            let block = allocator.allocate(small).unwrap();
+            match strategy {
+                FitStrategy::FirstFit => assert_eq!(block, a),
+                FitStrategy::BestFit => assert_eq!(block, b),
+            }
+        });
+    }
+}
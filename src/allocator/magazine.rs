@@ -0,0 +1,146 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    ops::Deref,
+    ptr,
+};
+
+use x86_64::instructions::interrupts;
+
+use super::fixed_size_block::{list_index, BLOCK_SIZES};
+
+/// Number of blocks a magazine holds
+const MAGAZINE_SIZE: usize = 32;
+const CLASSES: usize = BLOCK_SIZES.len();
+
+/// A stack of free blocks of one size class
+struct Magazine {
+    count: usize,
+    blocks: [*mut u8; MAGAZINE_SIZE],
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            blocks: [ptr::null_mut(); MAGAZINE_SIZE],
+        }
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        self.count = self.count.checked_sub(1)?;
+        Some(self.blocks[self.count])
+    }
+
+    fn push(&mut self, block: *mut u8) -> bool {
+        if self.count == MAGAZINE_SIZE {
+            return false;
+        }
+        self.blocks[self.count] = block;
+        self.count += 1;
+        true
+    }
+}
+
+/// The magazines of the cache, one per size class
+struct Cache {
+    magazines: [Magazine; CLASSES],
+}
+
+/// Returns the layout of the blocks of size class `idx`.
+fn class_layout(idx: usize) -> Layout {
+    Layout::from_size_align(BLOCK_SIZES[idx], BLOCK_SIZES[idx]).unwrap()
+}
+
+/// A front-end that keeps small freed blocks in a cache of magazines.
+///
+/// Allocations that a magazine can serve skip the lock of the inner
+/// allocator. The cache itself is protected by disabling interrupts, which
+/// is only enough as long as the kernel runs on a single processor. Empty or
+/// full magazines fall back to the inner allocator.
+pub struct Magazines<A> {
+    inner: A,
+    cache: UnsafeCell<Cache>,
+}
+
+// only the bootstrap processor runs kernel code, and it accesses the cache
+// with interrupts disabled, so no two accesses can overlap
+unsafe impl<A: Sync> Sync for Magazines<A> {}
+
+impl<A> Magazines<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            cache: UnsafeCell::new(Cache {
+                magazines: [const { Magazine::new() }; CLASSES],
+            }),
+        }
+    }
+
+    /// Run `f` on the cache with interrupts disabled.
+    fn with_cache<R>(&self, f: impl FnOnce(&mut Cache) -> R) -> R {
+        interrupts::without_interrupts(|| f(unsafe { &mut *self.cache.get() }))
+    }
+}
+
+impl<A: GlobalAlloc> Magazines<A> {
+    /// Return all cached blocks to the inner allocator.
+    pub fn flush(&self) {
+        for idx in 0..CLASSES {
+            while let Some(block) = self.with_cache(|cache| cache.magazines[idx].pop()) {
+                unsafe { self.inner.dealloc(block, class_layout(idx)) };
+            }
+        }
+    }
+
+    /// Returns the number of cached blocks.
+    pub fn cached_blocks(&self) -> usize {
+        self.with_cache(|cache| cache.magazines.iter().map(|m| m.count).sum())
+    }
+}
+
+impl<A> Deref for Magazines<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Magazines<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(idx) = list_index(&layout) else {
+            return self.inner.alloc(layout);
+        };
+        if let Some(block) = self.with_cache(|cache| cache.magazines[idx].pop()) {
+            return block;
+        }
+        let block = self.inner.alloc(class_layout(idx));
+        if !block.is_null() {
+            return block;
+        }
+        // the cached blocks of other classes may be all that is left
+        self.flush();
+        self.inner.alloc(class_layout(idx))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(idx) = list_index(&layout) else {
+            return self.inner.dealloc(ptr, layout);
+        };
+        // a full magazine hands half of its blocks back to the inner allocator
+        let mut overflow = [ptr::null_mut(); MAGAZINE_SIZE / 2];
+        self.with_cache(|cache| {
+            let magazine = &mut cache.magazines[idx];
+            if !magazine.push(ptr) {
+                for block in overflow.iter_mut() {
+                    *block = magazine.pop().unwrap();
+                }
+                magazine.push(ptr);
+            }
+        });
+        for block in overflow.into_iter().filter(|block| !block.is_null()) {
+            self.inner.dealloc(block, class_layout(idx));
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use alloc::alloc::{alloc, dealloc};
+use core::{
+    alloc::Layout,
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use super::Locked;
+
+/// Byte pattern of free objects in caches with poisoning
+const POISON_FREE: u8 = 0x6b;
+/// Minimum size of a slab
+const MIN_SLAB_SIZE: usize = 4096;
+/// Minimum number of objects per slab
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+struct FreeObject {
+    next: Option<&'static mut FreeObject>,
+}
+
+/// Header at the start of every slab
+struct SlabHeader {
+    /// Number of objects of this slab that are in the free list
+    free: usize,
+    next: Option<&'static mut SlabHeader>,
+}
+
+/// Statistics of a `KmemCache`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub name: &'static str,
+    /// Size of an object including padding
+    pub object_size: usize,
+    pub slabs: usize,
+    /// Number of objects in all slabs
+    pub objects_total: usize,
+    pub objects_in_use: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<16} {:>5} B {:>6}/{:<6} objects {:>4} slabs {:>8} allocs {:>8} frees",
+            self.name,
+            self.object_size,
+            self.objects_in_use,
+            self.objects_total,
+            self.slabs,
+            self.allocations,
+            self.frees
+        )
+    }
+}
+
+struct CacheState {
+    free: Option<&'static mut FreeObject>,
+    slabs: Option<&'static mut SlabHeader>,
+    stats: CacheStats,
+}
+
+/// A cache of equally sized objects of type `T`.
+///
+/// Objects are carved from slabs allocated on the heap, so they take exactly
+/// `size_of::<T>()` bytes (at least a pointer) instead of the next size class.
+pub struct KmemCache<T> {
+    constructor: Option<fn() -> T>,
+    poison: bool,
+    /// Keeps interrupts disabled while locked, as `grow` allocates with the
+    /// lock held and interrupt handlers may use the cache too
+    state: Locked<CacheState>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> KmemCache<T> {
+    const OBJECT_ALIGN: usize = if mem::align_of::<T>() > mem::align_of::<FreeObject>() {
+        mem::align_of::<T>()
+    } else {
+        mem::align_of::<FreeObject>()
+    };
+    const OBJECT_SIZE: usize = {
+        let size = if mem::size_of::<T>() > mem::size_of::<FreeObject>() {
+            mem::size_of::<T>()
+        } else {
+            mem::size_of::<FreeObject>()
+        };
+        (size + Self::OBJECT_ALIGN - 1) & !(Self::OBJECT_ALIGN - 1)
+    };
+    /// Offset of the first object in a slab
+    const FIRST_OBJECT: usize =
+        (mem::size_of::<SlabHeader>() + Self::OBJECT_ALIGN - 1) & !(Self::OBJECT_ALIGN - 1);
+    /// Size and alignment of a slab
+    const SLAB_SIZE: usize = {
+        let size =
+            (Self::FIRST_OBJECT + MIN_OBJECTS_PER_SLAB * Self::OBJECT_SIZE).next_power_of_two();
+        if size > MIN_SLAB_SIZE {
+            size
+        } else {
+            MIN_SLAB_SIZE
+        }
+    };
+    const CAPACITY: usize = (Self::SLAB_SIZE - Self::FIRST_OBJECT) / Self::OBJECT_SIZE;
+
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            constructor: None,
+            poison: false,
+            state: Locked::new(CacheState {
+                free: None,
+                slabs: None,
+                stats: CacheStats {
+                    name,
+                    object_size: Self::OBJECT_SIZE,
+                    slabs: 0,
+                    objects_total: 0,
+                    objects_in_use: 0,
+                    allocations: 0,
+                    frees: 0,
+                },
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Set the hook that initializes objects allocated by `alloc_constructed`.
+    pub const fn with_constructor(mut self, constructor: fn() -> T) -> Self {
+        self.constructor = Some(constructor);
+        self
+    }
+
+    /// Fill free objects with a poison pattern that is checked on
+    /// allocation, to catch writes after free.
+    pub const fn with_poisoning(mut self) -> Self {
+        self.poison = true;
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.state.lock().stats.name
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().stats
+    }
+
+    /// Allocate an object and move `value` into it.
+    ///
+    /// Returns `None` if the heap is exhausted.
+    pub fn alloc(&self, value: T) -> Option<CacheBox<'_, T>> {
+        let ptr = self.allocate()?;
+        unsafe { ptr.as_ptr().write(value) };
+        Some(CacheBox { cache: self, ptr })
+    }
+
+    /// Allocate an object initialized by the constructor hook.
+    ///
+    /// Panics if the cache has no constructor.
+    pub fn alloc_constructed(&self) -> Option<CacheBox<'_, T>> {
+        let constructor = self
+            .constructor
+            .unwrap_or_else(|| panic!("cache {} has no constructor", self.name()));
+        self.alloc(constructor())
+    }
+
+    fn slab_of(addr: usize) -> &'static mut SlabHeader {
+        unsafe { &mut *((addr & !(Self::SLAB_SIZE - 1)) as *mut SlabHeader) }
+    }
+
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(Self::SLAB_SIZE, Self::SLAB_SIZE).unwrap()
+    }
+
+    fn allocate(&self) -> Option<NonNull<T>> {
+        let mut state = self.state.lock();
+        if state.free.is_none() {
+            self.grow(&mut state)?;
+        }
+        let object = state.free.take().unwrap();
+        state.free = object.next.take();
+        let addr = object as *mut FreeObject as usize;
+        if self.poison {
+            self.check_poison(&state.stats, addr);
+        }
+        Self::slab_of(addr).free -= 1;
+        state.stats.objects_in_use += 1;
+        state.stats.allocations += 1;
+        NonNull::new(addr as *mut T)
+    }
+
+    /// Return the object at `ptr`, which must already be dropped.
+    unsafe fn free(&self, ptr: NonNull<T>) {
+        let mut state = self.state.lock();
+        let addr = ptr.as_ptr() as usize;
+        Self::slab_of(addr).free += 1;
+        self.push_free(&mut state, addr);
+        state.stats.objects_in_use -= 1;
+        state.stats.frees += 1;
+    }
+
+    fn push_free(&self, state: &mut CacheState, addr: usize) {
+        let object = FreeObject {
+            next: state.free.take(),
+        };
+        unsafe {
+            let object_ptr = addr as *mut FreeObject;
+            if self.poison {
+                let poisoned = (addr as *mut u8).add(mem::size_of::<FreeObject>());
+                poisoned.write_bytes(
+                    POISON_FREE,
+                    Self::OBJECT_SIZE - mem::size_of::<FreeObject>(),
+                );
+            }
+            object_ptr.write(object);
+            state.free = Some(&mut *object_ptr);
+        }
+    }
+
+    fn check_poison(&self, stats: &CacheStats, addr: usize) {
+        let len = Self::OBJECT_SIZE - mem::size_of::<FreeObject>();
+        let poisoned = unsafe {
+            core::slice::from_raw_parts((addr + mem::size_of::<FreeObject>()) as *const u8, len)
+        };
+        if let Some(offset) = poisoned.iter().position(|&b| b != POISON_FREE) {
+            panic!(
+                "cache {}: object {:#x} modified after free at offset {}",
+                stats.name,
+                addr,
+                offset + mem::size_of::<FreeObject>()
+            );
+        }
+    }
+
+    /// Allocate a new slab and add its objects to the free list.
+    fn grow(&self, state: &mut CacheState) -> Option<()> {
+        let slab_start = NonNull::new(unsafe { alloc(Self::slab_layout()) })?.as_ptr() as usize;
+        for i in (0..Self::CAPACITY).rev() {
+            self.push_free(
+                state,
+                slab_start + Self::FIRST_OBJECT + i * Self::OBJECT_SIZE,
+            );
+        }
+        let slab = SlabHeader {
+            free: Self::CAPACITY,
+            next: state.slabs.take(),
+        };
+        unsafe {
+            let slab_ptr = slab_start as *mut SlabHeader;
+            slab_ptr.write(slab);
+            state.slabs = Some(&mut *slab_ptr);
+        }
+        state.stats.slabs += 1;
+        state.stats.objects_total += Self::CAPACITY;
+        Some(())
+    }
+
+    /// Return all slabs without objects in use to the heap.
+    ///
+    /// Returns the number of bytes released.
+    pub fn shrink(&self) -> usize {
+        let mut state = self.state.lock();
+        let releasable = |addr: usize| Self::slab_of(addr).free == Self::CAPACITY;
+
+        let mut list = state.free.take();
+        while let Some(object) = list {
+            list = object.next.take();
+            let addr = object as *mut FreeObject as usize;
+            if !releasable(addr) {
+                object.next = state.free.take();
+                state.free = Some(object);
+            }
+        }
+
+        let mut released = 0;
+        let mut slabs = state.slabs.take();
+        while let Some(slab) = slabs {
+            slabs = slab.next.take();
+            let addr = slab as *mut SlabHeader as usize;
+            if releasable(addr) {
+                unsafe { dealloc(addr as *mut u8, Self::slab_layout()) };
+                state.stats.slabs -= 1;
+                state.stats.objects_total -= Self::CAPACITY;
+                released += Self::SLAB_SIZE;
+            } else {
+                slab.next = state.slabs.take();
+                state.slabs = Some(slab);
+            }
+        }
+        released
+    }
+}
+
+impl<T> Drop for KmemCache<T> {
+    fn drop(&mut self) {
+        // no `CacheBox` can outlive the cache, so all slabs are free
+        self.shrink();
+    }
+}
+
+/// An object allocated from a `KmemCache`, returned to it on drop
+pub struct CacheBox<'a, T> {
+    cache: &'a KmemCache<T>,
+    ptr: NonNull<T>,
+}
+
+impl<T> Deref for CacheBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CacheBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CacheBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for CacheBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.free(self.ptr);
+        }
+    }
+}
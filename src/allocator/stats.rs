@@ -0,0 +1,244 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    ops::Deref,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use super::fixed_size_block::{list_index, BLOCK_SIZES};
+use crate::serial_println;
+
+/// Number of size classes in `HeapStats::live_per_class`
+pub const SIZE_CLASSES: usize = BLOCK_SIZES.len() + 1;
+/// Maximum number of live allocations recorded by leak tracking
+const MAX_TRACKED: usize = 512;
+/// Frames between `Tracked::alloc` and the code that requested the memory
+const CALLER_FRAMES: usize = 2;
+
+static BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static FAILED_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static LIVE_PER_CLASS: [AtomicUsize; SIZE_CLASSES] = [const { AtomicUsize::new(0) }; SIZE_CLASSES];
+
+static LEAK_TRACKING: AtomicBool = AtomicBool::new(false);
+/// Allocations made while leak tracking was enabled and not freed yet
+static LIVE: spin::Mutex<[Option<LiveAllocation>; MAX_TRACKED]> =
+    spin::Mutex::new([None; MAX_TRACKED]);
+/// Allocations that did not fit into `LIVE`
+static UNTRACKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Usage statistics of the global allocator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Number of bytes mapped for the heap
+    pub heap_size: usize,
+    /// Requested bytes of all live allocations
+    pub bytes_in_use: usize,
+    pub peak_bytes: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub failed_allocations: u64,
+    /// Live allocations per size class of the fixed size block allocator,
+    /// the last entry counts larger allocations
+    pub live_per_class: [usize; SIZE_CLASSES],
+    /// Bytes used in the fallback heap, only known for the fixed size block
+    /// backend
+    pub fallback_bytes: Option<usize>,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "heap: {} B in use (peak {} B) of {} B mapped",
+            self.bytes_in_use, self.peak_bytes, self.heap_size
+        )?;
+        writeln!(
+            f,
+            "  {} allocations, {} deallocations, {} failed",
+            self.allocations, self.deallocations, self.failed_allocations
+        )?;
+        if let Some(fallback_bytes) = self.fallback_bytes {
+            writeln!(f, "  fallback: {} B", fallback_bytes)?;
+        }
+        write!(f, "  live per class:")?;
+        for (size, count) in BLOCK_SIZES.iter().zip(self.live_per_class) {
+            write!(f, " {}: {}", size, count)?;
+        }
+        write!(
+            f,
+            " >{}: {}",
+            BLOCK_SIZES[BLOCK_SIZES.len() - 1],
+            self.live_per_class[SIZE_CLASSES - 1]
+        )
+    }
+}
+
+/// Returns the current statistics of the global allocator.
+pub fn stats() -> HeapStats {
+    HeapStats {
+        heap_size: super::heap_size(),
+        bytes_in_use: BYTES_IN_USE.load(Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        failed_allocations: FAILED_ALLOCATIONS.load(Ordering::Relaxed),
+        live_per_class: core::array::from_fn(|i| LIVE_PER_CLASS[i].load(Ordering::Relaxed)),
+        fallback_bytes: super::fallback_bytes(),
+    }
+}
+
+/// An allocation recorded by leak tracking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveAllocation {
+    pub addr: usize,
+    pub size: usize,
+    /// Approximate return address into the code that allocated
+    pub caller: usize,
+}
+
+impl fmt::Display for LiveAllocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}: {} bytes from {:#x}",
+            self.addr, self.size, self.caller
+        )
+    }
+}
+
+/// Enable or disable recording of live allocations.
+///
+/// Enabling clears the allocations recorded so far. At most `MAX_TRACKED`
+/// allocations are recorded, the others are only counted.
+pub fn set_leak_tracking(enabled: bool) {
+    if enabled {
+        *LIVE.lock() = [None; MAX_TRACKED];
+        UNTRACKED.store(0, Ordering::Relaxed);
+    }
+    LEAK_TRACKING.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns the number of live allocations recorded by leak tracking.
+pub fn live_allocations() -> usize {
+    LIVE.lock().iter().flatten().count() + UNTRACKED.load(Ordering::Relaxed)
+}
+
+/// Print the allocations recorded by leak tracking to the serial port.
+///
+/// Does not allocate, so it can be used while the heap is broken.
+pub fn dump_live_allocations() {
+    let live = LIVE.lock();
+    serial_println!("live allocations:");
+    for allocation in live.iter().flatten() {
+        serial_println!("  {}", allocation);
+    }
+    let untracked = UNTRACKED.load(Ordering::Relaxed);
+    if untracked > 0 {
+        serial_println!("  {} more not recorded", untracked);
+    }
+}
+
+/// Returns the return address `CALLER_FRAMES` frames above the calling
+/// function, found by following the frame pointers.
+#[inline(always)]
+fn caller_address() -> usize {
+    let mut frame: usize;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags));
+    }
+    let mut caller = 0;
+    for _ in 0..=CALLER_FRAMES {
+        if frame == 0 || !frame.is_multiple_of(8) {
+            break;
+        }
+        let words = frame as *const usize;
+        unsafe {
+            caller = words.add(1).read();
+            frame = words.read();
+        }
+    }
+    caller
+}
+
+fn class_index(layout: &Layout) -> usize {
+    list_index(layout).unwrap_or(SIZE_CLASSES - 1)
+}
+
+/// A wrapper around an allocator that records the statistics reported by
+/// `stats`
+pub struct Tracked<A> {
+    inner: A,
+}
+
+impl<A> Tracked<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+impl<A> Deref for Tracked<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Tracked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() {
+            FAILED_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            return ptr;
+        }
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        let in_use = BYTES_IN_USE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK_BYTES.fetch_max(in_use, Ordering::Relaxed);
+        LIVE_PER_CLASS[class_index(&layout)].fetch_add(1, Ordering::Relaxed);
+
+        if LEAK_TRACKING.load(Ordering::Relaxed) {
+            let allocation = LiveAllocation {
+                addr: ptr as usize,
+                size: layout.size(),
+                caller: caller_address(),
+            };
+            interrupts::without_interrupts(|| {
+                match LIVE.lock().iter_mut().find(|slot| slot.is_none()) {
+                    Some(slot) => *slot = Some(allocation),
+                    None => {
+                        UNTRACKED.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+        LIVE_PER_CLASS[class_index(&layout)].fetch_sub(1, Ordering::Relaxed);
+
+        if LEAK_TRACKING.load(Ordering::Relaxed) {
+            interrupts::without_interrupts(|| {
+                let mut live = LIVE.lock();
+                if let Some(slot) = live
+                    .iter_mut()
+                    .find(|slot| slot.is_some_and(|a| a.addr == ptr as usize))
+                {
+                    *slot = None;
+                } else {
+                    // the allocation was only counted, if it was tracked at all
+                    let _ = UNTRACKED
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+                }
+            });
+        }
+    }
+}
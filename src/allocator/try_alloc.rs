@@ -0,0 +1,83 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{alloc::Layout, fmt};
+
+/// The global allocator could not satisfy an allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError {
+    pub layout: Layout,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "out of memory allocating {} bytes (align {})",
+            self.layout.size(),
+            self.layout.align()
+        )
+    }
+}
+
+/// Move `value` to the heap, returning an error instead of panicking if the
+/// heap is exhausted.
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+    let ptr = unsafe { alloc::alloc::alloc(layout) } as *mut T;
+    if ptr.is_null() {
+        return Err(AllocError { layout });
+    }
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Create an empty vector with room for exactly `capacity` elements.
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    let mut vec = Vec::new();
+    try_reserve(&mut vec, capacity)?;
+    Ok(vec)
+}
+
+/// Reserve room for `additional` more elements in `vec`.
+pub fn try_reserve<T>(vec: &mut Vec<T>, additional: usize) -> Result<(), AllocError> {
+    vec.try_reserve_exact(additional).map_err(|_| AllocError {
+        layout: Layout::array::<T>(vec.len().saturating_add(additional))
+            .unwrap_or(Layout::new::<T>()),
+    })
+}
+
+/// Cloning that reports allocation failures instead of panicking
+pub trait TryClone: Sized {
+    fn try_clone(&self) -> Result<Self, AllocError>;
+}
+
+impl<T: Clone> TryClone for Box<T> {
+    fn try_clone(&self) -> Result<Self, AllocError> {
+        try_box(T::clone(self))
+    }
+}
+
+impl<T: Clone> TryClone for Vec<T> {
+    fn try_clone(&self) -> Result<Self, AllocError> {
+        let mut vec = try_vec_with_capacity(self.len())?;
+        vec.extend_from_slice(self);
+        Ok(vec)
+    }
+}
+
+impl TryClone for String {
+    fn try_clone(&self) -> Result<Self, AllocError> {
+        let mut string = String::new();
+        string
+            .try_reserve_exact(self.len())
+            .map_err(|_| AllocError {
+                layout: Layout::array::<u8>(self.len()).unwrap(),
+            })?;
+        string.push_str(self);
+        Ok(string)
+    }
+}
@@ -0,0 +1,91 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::{serial_println, Testable};
+
+/// A benchmark that runs in the test framework.
+///
+/// Registered with `#[test_case]` on a static, it runs `run` repeatedly and
+/// prints the measured cycles as a row of a table over serial:
+///
+/// ```ignore
+/// #[test_case]
+/// static SMALL_BOXES: Bench = Bench::new("small boxes", small_boxes);
+/// ```
+pub struct Bench {
+    name: &'static str,
+    iterations: u32,
+    run: fn(),
+}
+
+impl Bench {
+    pub const fn new(name: &'static str, run: fn()) -> Self {
+        Self {
+            name,
+            iterations: 10,
+            run,
+        }
+    }
+
+    /// Set the number of measured runs, 10 by default.
+    pub const fn iterations(mut self, iterations: u32) -> Self {
+        assert!(iterations > 0, "a benchmark needs at least one run");
+        self.iterations = iterations;
+        self
+    }
+}
+
+/// Read the time stamp counter.
+///
+/// The fences keep the read from being reordered with the measured code.
+fn rdtsc() -> u64 {
+    unsafe {
+        asm!("lfence", options(nostack, preserves_flags));
+        let tsc = core::arch::x86_64::_rdtsc();
+        asm!("lfence", options(nostack, preserves_flags));
+        tsc
+    }
+}
+
+impl Testable for Bench {
+    fn run(&self) {
+        static HEADER_PRINTED: AtomicBool = AtomicBool::new(false);
+        if !HEADER_PRINTED.swap(true, Ordering::Relaxed) {
+            serial_println!(
+                "{:<32} {:>6} {:>14} {:>14} {:>14}",
+                "bench",
+                "runs",
+                "min cycles",
+                "avg cycles",
+                "max cycles"
+            );
+        }
+
+        // warm up caches and the heap
+        (self.run)();
+        let (mut min, mut max, mut total) = (u64::MAX, 0, 0);
+        for _ in 0..self.iterations {
+            // keep interrupt handlers out of the measurement
+            let cycles = interrupts::without_interrupts(|| {
+                let start = rdtsc();
+                (self.run)();
+                rdtsc() - start
+            });
+            min = min.min(cycles);
+            max = max.max(cycles);
+            total += cycles;
+        }
+        serial_println!(
+            "{:<32} {:>6} {:>14} {:>14} {:>14}",
+            self.name,
+            self.iterations,
+            min,
+            total / u64::from(self.iterations),
+            max
+        );
+    }
+}
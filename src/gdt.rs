@@ -1,32 +1,37 @@
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory::stack::{self, StackBounds, StackError};
+
 /// The index of the IST entry for double faults
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Size of the double fault stack in pages
+const DOUBLE_FAULT_STACK_PAGES: u64 = 5;
 
-lazy_static! {
-    /// The TSS for double faults
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE as u64;
-            // stack grows downwards
-            stack_end
-        };
-        tss
-    };
-}
+/// The TSS for double faults, only written by `init` and
+/// `init_double_fault_stack`
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+/// The guarded double fault stack, never freed
+static DOUBLE_FAULT_STACK: spin::Once<StackBounds> = spin::Once::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        // double faults use a static stack until paging is set up
+        const STACK_SIZE: usize = 4096 * DOUBLE_FAULT_STACK_PAGES as usize;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        let stack_end = stack_start + STACK_SIZE as u64;
+        // stack grows downwards
+        unsafe {
+            TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end;
+        }
+
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(&raw const TSS) });
         (
             gdt,
             Selectors {
@@ -52,3 +57,19 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Switch double faults from the static boot stack to a stack with a guard
+/// page.
+///
+/// Needs `memory::install_kernel_paging`. Returns the new stack, which is
+/// only allocated by the first call.
+pub fn init_double_fault_stack() -> Result<&'static StackBounds, StackError> {
+    let stack =
+        DOUBLE_FAULT_STACK.try_call_once(|| stack::alloc_stack(DOUBLE_FAULT_STACK_PAGES))?;
+    let end = stack.end();
+    // the CPU reads the entry from the loaded TSS on every double fault
+    interrupts::without_interrupts(|| unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = end;
+    });
+    Ok(stack)
+}
@@ -1,9 +1,14 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
-use crate::{gdt, hlt_loop, print, println};
+use crate::{gdt, hlt_loop, memory, print, println};
+
+pub mod apic;
+mod madt;
 
 /// Primary PIC
 ///
@@ -15,6 +20,12 @@ pub const PIC_1_OFFSET: u8 = 32;
 /// Map the PICS to the interrupt vector 40 - 55
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Vector of spurious interrupts of the primary PIC (IRQ 7)
+const PIC_1_SPURIOUS_VECTOR: u8 = PIC_1_OFFSET + 7;
+
+/// Vector of spurious interrupts of the secondary PIC (IRQ 15)
+const PIC_2_SPURIOUS_VECTOR: u8 = PIC_2_OFFSET + 7;
+
 /// chained PICs
 pub const PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
@@ -32,6 +43,11 @@ lazy_static! {
         }
         idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_u8()].set_handler_fn(serial_interrupt_handler);
+        idt[apic::SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
+        // the PICs raise these even when they are masked
+        idt[PIC_1_SPURIOUS_VECTOR].set_handler_fn(pic_1_spurious_interrupt_handler);
+        idt[PIC_2_SPURIOUS_VECTOR].set_handler_fn(pic_2_spurious_interrupt_handler);
         idt
     };
 }
@@ -40,6 +56,26 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Number of timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Signal the end of interrupt `index` to the local APIC, or to the PICs if
+/// `apic::init` did not succeed.
+fn notify_end_of_interrupt(index: InterruptIndex) {
+    match apic::local_apic() {
+        Some(local_apic) => local_apic.end_of_interrupt(),
+        // Figures out whether the primary or secondary PIC sent
+        // the interrupt and then uses the command and data ports
+        // to send an EOI signal to the respective controllers.
+        None => unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) },
+    }
+}
+
 /// Breakpoint exception handler
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
@@ -50,6 +86,13 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // a fault in a guard page can't push its exception frame on the
+    // overflowed stack and escalates to a double fault
+    if let Some(id) = Cr2::read().ok().and_then(memory::stack::guard_page_hit) {
+        println!("EXCEPTION: DOUBLE FAULT (stack overflow in stack {})", id);
+    }
     println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
     loop {}
 }
@@ -59,9 +102,20 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    use memory::fault::{handle_page_fault, FaultResolution};
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
+    if let Ok(addr) = Cr2::read() {
+        match handle_page_fault(addr, error_code) {
+            FaultResolution::Resolved => return,
+            FaultResolution::StackOverflow(id) => {
+                println!("EXCEPTION: PAGE FAULT (stack overflow in stack {})", id)
+            }
+            FaultResolution::Fatal => println!("EXCEPTION: PAGE FAULT"),
+        }
+    } else {
+        println!("EXCEPTION: PAGE FAULT");
+    }
     // Cr2 register contains the faulting virtual address
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
@@ -72,13 +126,8 @@ extern "x86-interrupt" fn page_fault_handler(
 /// Timer interrupt handler
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     print!(".");
-    // Figures out whether the primary or secondary PIC sent
-    // the interrupt and then uses the command and data ports
-    // to send an EOI signal to the respective controllers.
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    notify_end_of_interrupt(InterruptIndex::Timer);
 }
 
 /// Keyboard interrupt handler
@@ -112,10 +161,29 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     //     }
     // }
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    notify_end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+/// Serial port interrupt handler, echoes the received bytes
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::serial::receive_pending(|byte| print!("{}", byte as char));
+    notify_end_of_interrupt(InterruptIndex::Serial);
+}
+
+/// Spurious interrupt handler of the local APIC, which must not be
+/// acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// Spurious interrupt handler of the primary PIC, which must not be
+/// acknowledged
+extern "x86-interrupt" fn pic_1_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// Spurious interrupt handler of the secondary PIC
+///
+/// The primary PIC saw a real interrupt on the cascade line, so only it is
+/// acknowledged.
+extern "x86-interrupt" fn pic_2_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET) };
 }
 
 #[test_case]
@@ -123,6 +191,14 @@ fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
 
+#[test_case]
+fn test_spurious_pic_interrupts() {
+    unsafe {
+        core::arch::asm!("int {}", const PIC_1_SPURIOUS_VECTOR);
+        core::arch::asm!("int {}", const PIC_2_SPURIOUS_VECTOR);
+    }
+}
+
 /// Interrupt index
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -132,6 +208,9 @@ pub enum InterruptIndex {
 
     /// Keyboard interrupt
     Keyboard,
+
+    /// Serial port (COM1) interrupt
+    Serial = PIC_1_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -139,6 +218,11 @@ impl InterruptIndex {
         self as u8
     }
 
+    /// Returns the ISA IRQ number of the interrupt.
+    fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
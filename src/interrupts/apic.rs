@@ -0,0 +1,236 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use x86_64::{instructions::interrupts, registers::model_specific::Msr};
+
+use super::{madt::Madt, InterruptIndex, PICS};
+use crate::memory::{map_mmio, CacheMode, MmioError, MmioRegion};
+
+/// Vector of spurious interrupts of the local APIC; its low nibble must be
+/// all ones on older processors
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The APIC base MSR
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Global enable bit of the APIC base MSR
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const LAPIC_SIZE: usize = 0x400;
+const LAPIC_ID: usize = 0x20;
+/// Task priority register
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+/// Spurious interrupt vector register
+const LAPIC_SVR: usize = 0xf0;
+/// Software enable bit of the spurious interrupt vector register
+const SVR_ENABLE: u32 = 1 << 8;
+
+const IOAPIC_SIZE: usize = 0x20;
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+/// First register of the redirection table, two registers per entry
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECT_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECT_LEVEL: u64 = 1 << 15;
+const REDIRECT_MASKED: u64 = 1 << 16;
+
+/// The ISA interrupts routed through the I/O APIC by `init`
+const ISA_ROUTES: [InterruptIndex; 3] = [
+    InterruptIndex::Timer,
+    InterruptIndex::Keyboard,
+    InterruptIndex::Serial,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// The CPU has no local APIC
+    NotSupported,
+    /// The ACPI tables have no MADT
+    NoMadt,
+    /// No I/O APIC handles the global system interrupt
+    NoIoApic(u32),
+    Mmio(MmioError),
+}
+
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApicError::NotSupported => write!(f, "the CPU has no local APIC"),
+            ApicError::NoMadt => write!(f, "no MADT in the ACPI tables"),
+            ApicError::NoIoApic(gsi) => write!(f, "no I/O APIC handles GSI {}", gsi),
+            ApicError::Mmio(err) => write!(f, "mapping the APIC registers failed: {:?}", err),
+        }
+    }
+}
+
+/// The local APIC of the bootstrap processor
+pub struct LocalApic {
+    regs: MmioRegion,
+}
+
+impl LocalApic {
+    /// Returns the APIC id of the processor.
+    pub fn id(&self) -> u8 {
+        (self.regs.read::<u32>(LAPIC_ID) >> 24) as u8
+    }
+
+    /// Signal the end of the interrupt being handled.
+    pub fn end_of_interrupt(&self) {
+        self.regs.write::<u32>(LAPIC_EOI, 0);
+    }
+
+    fn enable(&self) {
+        let mut base = Msr::new(IA32_APIC_BASE);
+        unsafe {
+            let value = base.read();
+            if value & APIC_BASE_ENABLE == 0 {
+                base.write(value | APIC_BASE_ENABLE);
+            }
+        }
+        // accept all interrupts
+        self.regs.write::<u32>(LAPIC_TPR, 0);
+        self.regs
+            .write::<u32>(LAPIC_SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+    }
+}
+
+struct IoApic {
+    regs: MmioRegion,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        self.regs.write(IOREGSEL, reg);
+        self.regs.read(IOWIN)
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        self.regs.write(IOREGSEL, reg);
+        self.regs.write(IOWIN, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION + 2 * (gsi - self.gsi_base);
+        // mask the entry while it is half written
+        self.write(reg, REDIRECT_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+struct Apic {
+    local: LocalApic,
+    io_apics: spin::Mutex<Vec<IoApic>>,
+    madt: Madt,
+}
+
+static APIC: spin::Once<Apic> = spin::Once::new();
+
+/// Returns the local APIC if the interrupts are delivered through it.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    APIC.get().map(|apic| &apic.local)
+}
+
+/// Returns whether the CPU has a local APIC.
+fn apic_supported() -> bool {
+    core::arch::x86_64::__cpuid(1).edx & (1 << 9) != 0
+}
+
+/// Switch from the 8259 PICs to the local and I/O APICs.
+///
+/// Masks the PICs and routes the timer, keyboard and serial IRQs to their
+/// usual vectors, so the handlers stay the same. Needs the kernel paging
+/// state to map the registers; on error the PICs stay in use.
+pub fn init() -> Result<(), ApicError> {
+    if APIC.is_completed() {
+        return Ok(());
+    }
+    if !apic_supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = Madt::find().ok_or(ApicError::NoMadt)?;
+    let local = LocalApic {
+        regs: map_mmio(madt.local_apic, LAPIC_SIZE, CacheMode::Uncached)
+            .map_err(ApicError::Mmio)?,
+    };
+    let mut io_apics = Vec::new();
+    for entry in &madt.io_apics {
+        let regs =
+            map_mmio(entry.address, IOAPIC_SIZE, CacheMode::Uncached).map_err(ApicError::Mmio)?;
+        let mut io_apic = IoApic {
+            regs,
+            gsi_base: entry.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = (io_apic.read(IOAPIC_VERSION) >> 16 & 0xff) + 1;
+        io_apics.push(io_apic);
+    }
+    for index in ISA_ROUTES {
+        let (gsi, _) = isa_gsi(&madt, index.irq());
+        if !io_apics.iter().any(|io_apic| io_apic.handles(gsi)) {
+            return Err(ApicError::NoIoApic(gsi));
+        }
+    }
+
+    interrupts::without_interrupts(|| {
+        if madt.has_8259 {
+            unsafe { PICS.lock().disable() };
+        }
+        for io_apic in &io_apics {
+            for i in 0..io_apic.entries {
+                io_apic.set_redirection(io_apic.gsi_base + i, REDIRECT_MASKED);
+            }
+        }
+        local.enable();
+        APIC.call_once(|| Apic {
+            local,
+            io_apics: spin::Mutex::new(io_apics),
+            madt,
+        });
+        for index in ISA_ROUTES {
+            route_isa_irq(index.irq(), index.as_u8()).expect("checked above");
+        }
+    });
+    Ok(())
+}
+
+/// Returns the global system interrupt and the redirection entry flags of
+/// ISA `irq`.
+fn isa_gsi(madt: &Madt, irq: u8) -> (u32, u64) {
+    // ISA interrupts are active high and edge triggered unless overridden
+    let Some(source_override) = madt.overrides.iter().find(|o| o.irq == irq) else {
+        return (irq.into(), 0);
+    };
+    let mut flags = 0;
+    if source_override.flags & 0b11 == 0b11 {
+        flags |= REDIRECT_ACTIVE_LOW;
+    }
+    if source_override.flags >> 2 & 0b11 == 0b11 {
+        flags |= REDIRECT_LEVEL;
+    }
+    (source_override.gsi, flags)
+}
+
+/// Deliver ISA `irq` as `vector` to the bootstrap processor.
+pub fn route_isa_irq(irq: u8, vector: u8) -> Result<(), ApicError> {
+    let apic = APIC.get().ok_or(ApicError::NotSupported)?;
+    let (gsi, flags) = isa_gsi(&apic.madt, irq);
+    let entry = u64::from(apic.local.id()) << 56 | flags | u64::from(vector);
+    interrupts::without_interrupts(|| {
+        let io_apics = apic.io_apics.lock();
+        let io_apic = io_apics
+            .iter()
+            .find(|io_apic| io_apic.handles(gsi))
+            .ok_or(ApicError::NoIoApic(gsi))?;
+        io_apic.set_redirection(gsi, entry);
+        Ok(())
+    })
+}
@@ -0,0 +1,144 @@
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr};
+
+use x86_64::PhysAddr;
+
+use crate::memory::{self, TranslateResult};
+
+/// Size of the header shared by all ACPI system description tables
+const SDT_HEADER_SIZE: u64 = 36;
+/// Offset of the first interrupt controller structure in the MADT
+const MADT_ENTRIES: u64 = 44;
+/// MADT flag: the system also has dual 8259 PICs
+const PCAT_COMPAT: u32 = 1;
+
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_OVERRIDE: u8 = 5;
+
+/// An I/O APIC described by the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is not identity mapped to a global system interrupt
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3
+    pub flags: u16,
+}
+
+/// The interrupt controllers of the multiple APIC description table
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+    /// Whether the legacy PICs are present and must be masked
+    pub has_8259: bool,
+}
+
+/// Read a `T` from physical memory through the physical memory window.
+///
+/// Returns `None` if the bytes are not mapped, which happens for firmware
+/// tables above the memory map.
+fn read_phys<T: Copy>(addr: u64) -> Option<T> {
+    let start = memory::physical_memory_offset() + addr;
+    let end = start + (size_of::<T>() as u64 - 1);
+    for page_addr in [start, end] {
+        if let TranslateResult::NotMapped = memory::translate(page_addr) {
+            return None;
+        }
+    }
+    Some(unsafe { ptr::read_unaligned(start.as_ptr()) })
+}
+
+/// Returns whether the `len` bytes at `addr` sum up to zero.
+fn checksum_ok(addr: u64, len: u64) -> bool {
+    (addr..addr + len)
+        .map(read_phys::<u8>)
+        .try_fold(0u8, |sum, byte| Some(sum.wrapping_add(byte?)))
+        == Some(0)
+}
+
+/// Search the BIOS areas for the root system description pointer.
+fn find_rsdp() -> Option<u64> {
+    let ebda = u64::from(read_phys::<u16>(0x40e)?) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    areas.into_iter().find_map(|(start, end)| {
+        (start..end)
+            .step_by(16)
+            .find(|&addr| read_phys::<[u8; 8]>(addr) == Some(*b"RSD PTR ") && checksum_ok(addr, 20))
+    })
+}
+
+/// Returns the address of the table with `signature` in the RSDT or XSDT.
+fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    let rsdp = find_rsdp()?;
+    let revision = read_phys::<u8>(rsdp + 15)?;
+    let (root, entry_size) =
+        if revision >= 2 && checksum_ok(rsdp, read_phys::<u32>(rsdp + 20)?.into()) {
+            (read_phys::<u64>(rsdp + 24)?, 8)
+        } else {
+            (read_phys::<u32>(rsdp + 16)?.into(), 4)
+        };
+    let root_len = u64::from(read_phys::<u32>(root + 4)?);
+    if !checksum_ok(root, root_len) {
+        return None;
+    }
+
+    (root + SDT_HEADER_SIZE..root + root_len)
+        .step_by(entry_size)
+        .filter_map(|entry| match entry_size {
+            8 => read_phys::<u64>(entry),
+            _ => read_phys::<u32>(entry).map(u64::from),
+        })
+        .find(|&table| {
+            read_phys::<[u8; 4]>(table) == Some(*signature)
+                && read_phys::<u32>(table + 4).is_some_and(|len| checksum_ok(table, len.into()))
+        })
+}
+
+impl Madt {
+    /// Find and parse the MADT of the ACPI tables.
+    pub fn find() -> Option<Madt> {
+        let table = find_table(b"APIC")?;
+        let len = u64::from(read_phys::<u32>(table + 4)?);
+        let mut madt = Madt {
+            local_apic: PhysAddr::new(read_phys::<u32>(table + 36)?.into()),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            has_8259: read_phys::<u32>(table + 40)? & PCAT_COMPAT != 0,
+        };
+
+        let mut entry = table + MADT_ENTRIES;
+        while entry + 2 <= table + len {
+            let [kind, entry_len] = read_phys::<[u8; 2]>(entry)?;
+            if entry_len < 2 {
+                break;
+            }
+            match kind {
+                ENTRY_IO_APIC => madt.io_apics.push(IoApicEntry {
+                    address: PhysAddr::new(read_phys::<u32>(entry + 4)?.into()),
+                    gsi_base: read_phys(entry + 8)?,
+                }),
+                ENTRY_INTERRUPT_OVERRIDE => madt.overrides.push(InterruptOverride {
+                    irq: read_phys(entry + 3)?,
+                    gsi: read_phys(entry + 4)?,
+                    flags: read_phys(entry + 8)?,
+                }),
+                ENTRY_LOCAL_APIC_OVERRIDE => {
+                    madt.local_apic = PhysAddr::new(read_phys(entry + 4)?);
+                }
+                _ => {}
+            }
+            entry += u64::from(entry_len);
+        }
+        Some(madt)
+    }
+}
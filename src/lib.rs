@@ -4,6 +4,8 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 
 extern crate alloc;
 use core::panic::PanicInfo;
@@ -11,6 +13,7 @@ use core::panic::PanicInfo;
 use bootloader::{entry_point, BootInfo};
 
 pub mod allocator;
+pub mod bench;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
@@ -8,7 +8,7 @@ extern crate alloc;
 use alloc::{boxed::Box, rc::Rc, string::ToString, vec, vec::Vec};
 
 use blog_os::{
-    allocator,
+    allocator, gdt, interrupts,
     memory::{self, EmptyFrameAllocator},
     println,
     task::{keyboard::print_keypresses, simple_executor::SimpleExecutor, Task},
@@ -47,15 +47,30 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
 
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
 
     unsafe {
+        memory::protect::protect_kernel(&mut mapper);
         allocator::init_heap(&mut mapper, &mut frame_allocator).unwrap();
     }
+    memory::install_kernel_paging(mapper, frame_allocator);
+    gdt::init_double_fault_stack().expect("failed to allocate the double fault stack");
+    if let Err(err) = interrupts::apic::init() {
+        println!("APIC unavailable ({}), using the 8259 PICs", err);
+    }
+    memory::print_memory_map();
 
     let mut executor = SimpleExecutor::new();
-    executor.spawn(Task::new(async_task()));
-    executor.spawn(Task::new(print_keypresses()));
+    for task in [
+        Task::try_new(async_task()),
+        Task::try_new(print_keypresses()),
+    ] {
+        match task {
+            Ok(task) => executor.spawn(task),
+            Err(err) => println!("failed to spawn task: {}", err),
+        }
+    }
     executor.run();
 
     #[cfg(test)]
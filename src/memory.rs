@@ -1,45 +1,106 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use x86_64::{
     registers::control::Cr3,
     structures::paging::{
-        page_table::FrameError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
-        PhysFrame, Size4KiB,
+        frame::PhysFrameRange, page_table::PageTableEntry, FrameAllocator, FrameDeallocator,
+        Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB,
+        Size2MiB, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::bootinfo::MemoryMap;
+
+use buddy::BuddyFrameAllocator;
+
+pub mod address_space;
+pub mod buddy;
+pub mod cow;
+pub mod dump;
+pub mod fault;
+pub mod lazy;
+mod mapping;
+mod mmio;
+pub mod protect;
+pub mod stack;
+mod stats;
+pub mod vmm;
+
+pub use dump::dump_page_tables;
+pub use mapping::{remap_range, unmap_range, FLUSH_ALL_THRESHOLD};
+pub use mmio::{map_mmio, CacheMode, MmioError, MmioRegion};
+pub use stats::{print_memory_map, region_bytes, stats, MemoryStats};
 
 /// A FrameAllocator that returns frames from the memory map
+///
+/// The usable regions are managed by a `BuddyFrameAllocator`, so deallocated
+/// frames are merged and handed out again, and runs of contiguous frames can
+/// be allocated too.
 pub struct BootInfoFrameAllocator {
     /// Passed by the bootloader
     memory_map: &'static MemoryMap,
-    /// Increase for every frame allocated
-    next: usize,
+    buddy: BuddyFrameAllocator,
+    /// Number of frames managed by `buddy`
+    usable_frames: u64,
 }
 
 impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+    /// Create a frame allocator from the passed memory map.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the memory map is valid, that all frames
+    /// marked as `Usable` are really unused and that the complete physical
+    /// memory is mapped at `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let buddy = BuddyFrameAllocator::init(memory_map, physical_memory_offset);
         Self {
             memory_map,
-            next: 0,
+            usable_frames: buddy.free_frames(),
+            buddy,
         }
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get regions from the memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    /// Returns the memory map the allocator was created from.
+    pub fn memory_map(&self) -> &'static MemoryMap {
+        self.memory_map
+    }
+
+    /// Returns the number of frames currently allocated.
+    pub fn allocated_frames(&self) -> u64 {
+        self.usable_frames - self.buddy.free_frames()
+    }
+
+    /// Returns the number of usable frames that are not allocated.
+    pub fn free_frames(&self) -> u64 {
+        self.buddy.free_frames()
+    }
+
+    /// Allocate `count` physically contiguous frames.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        self.buddy.allocate_contiguous(count)
+    }
+
+    /// Free a run returned by `allocate_contiguous`.
+    ///
+    /// # Safety
+    ///
+    /// The frames must not be in use anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        self.buddy.deallocate_contiguous(range);
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        self.buddy.allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.buddy.deallocate_frame(frame);
     }
 }
 
@@ -59,40 +120,124 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
-// pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-//     translate_addr_inner(addr, physical_memory_offset)
-// }
-
-/// Translate a virtual address to a physical address by walking the page table chain
-// fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-//     // get the level 4 page table frame from the CR3 register
-//     let (level_4_table_frame, _) = Cr3::read();
-
-//     let table_indexes = [
-//         addr.p4_index(),
-//         addr.p3_index(),
-//         addr.p2_index(),
-//         addr.p1_index(),
-//     ];
-
-//     let mut frame = level_4_table_frame;
-
-//     for &index in table_indexes.iter() {
-//         // get the page table start virtual address
-//         let virt = physical_memory_offset + frame.start_address().as_u64();
-//         let table_ptr: *const PageTable = virt.as_ptr();
-//         let table = unsafe { &*table_ptr };
-
-//         let entry = &table[index];
-//         // update the table frame to the next level
-//         frame = match entry.frame() {
-//             Ok(f) => f,
-//             Err(FrameError::FrameNotPresent) => return None,
-//             Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
-//         }
-//     }
-//     Some(frame.start_address() + addr.page_offset().into())
-// }
+/// Returns the page table the given entry points to, or `None` if the entry
+/// is unused or maps a huge page.
+///
+/// # Safety
+///
+/// `init` must have been called and the caller must ensure that no other
+/// reference to the table exists.
+pub(crate) unsafe fn next_table(entry: &PageTableEntry) -> Option<&'static mut PageTable> {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+        return None;
+    }
+    let virt = physical_memory_offset() + entry.addr().as_u64();
+    Some(&mut *virt.as_mut_ptr::<PageTable>())
+}
+
+/// Size of the page mapping a virtual address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedSize {
+    Page4KiB,
+    Page2MiB,
+    Page1GiB,
+}
+
+impl MappedSize {
+    /// Returns the size in bytes.
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappedSize::Page4KiB => Size4KiB::SIZE,
+            MappedSize::Page2MiB => Size2MiB::SIZE,
+            MappedSize::Page1GiB => Size1GiB::SIZE,
+        }
+    }
+}
+
+/// Result of `translate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslateResult {
+    Mapped {
+        /// Physical address the virtual address maps to
+        phys: PhysAddr,
+        /// Size of the page containing the address
+        size: MappedSize,
+        /// Flags of the leaf page table entry
+        flags: PageTableFlags,
+    },
+    NotMapped,
+}
+
+impl TranslateResult {
+    /// Returns the physical address, if the address is mapped.
+    pub fn phys_addr(&self) -> Option<PhysAddr> {
+        match *self {
+            TranslateResult::Mapped { phys, .. } => Some(phys),
+            TranslateResult::NotMapped => None,
+        }
+    }
+}
+
+/// Virtual address at which the physical memory is mapped, set by `init`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the virtual address at which the physical memory is mapped.
+///
+/// Panics if `init` was not called yet.
+pub fn physical_memory_offset() -> VirtAddr {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => panic!("memory::init was not called"),
+        offset => VirtAddr::new(offset),
+    }
+}
+
+/// Translate a virtual address by walking the active page table chain.
+///
+/// Unlike the `Translate` impl of `OffsetPageTable`, this needs no lock and
+/// can be used from any context once `init` was called.
+pub fn translate(addr: VirtAddr) -> TranslateResult {
+    let physical_memory_offset = physical_memory_offset();
+    // get the level 4 page table frame from the CR3 register
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let table_indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    let mut table_addr = level_4_table_frame.start_address();
+
+    for (level, &index) in table_indexes.iter().enumerate() {
+        // get the page table start virtual address
+        let virt = physical_memory_offset + table_addr.as_u64();
+        let table = unsafe { &*virt.as_ptr::<PageTable>() };
+
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return TranslateResult::NotMapped;
+        }
+        let size = match level {
+            1 if flags.contains(PageTableFlags::HUGE_PAGE) => MappedSize::Page1GiB,
+            2 if flags.contains(PageTableFlags::HUGE_PAGE) => MappedSize::Page2MiB,
+            3 => MappedSize::Page4KiB,
+            // descend to the next level
+            _ => {
+                table_addr = entry.addr();
+                continue;
+            }
+        };
+        return TranslateResult::Mapped {
+            phys: entry.addr() + (addr.as_u64() & (size.bytes() - 1)),
+            size,
+            flags,
+        };
+    }
+    unreachable!("level 1 entries are always leaves")
+}
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -100,10 +245,71 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 ///
 /// This function is unsafe because it may cause a page fault.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// The kernel page table and the frame allocator backing it, shared by the
+/// subsystems that map pages after boot (e.g. heap growth, demand paging).
+static KERNEL_PAGING: spin::Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> =
+    spin::Mutex::new(None);
+
+/// Hand the kernel mapper and frame allocator over to the memory subsystem.
+///
+/// Must be called once the boot-time mappings (e.g. the initial heap) are set
+/// up; from then on all mapping changes go through `with_kernel_paging`. Also
+/// reserves the bootloader's physical memory mapping in the `vmm`.
+pub fn install_kernel_paging(
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+) {
+    let physical_memory_size = frame_allocator
+        .memory_map
+        .iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    vmm::reserve(
+        mapper.phys_offset(),
+        PhysAddr::new(physical_memory_size)
+            .align_up(4096u64)
+            .as_u64(),
+        vmm::RegionKind::PhysicalMemory,
+    )
+    .expect("physical memory mapping overlaps another region");
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *KERNEL_PAGING.lock() = Some((mapper, frame_allocator));
+    });
+}
+
+/// Run `f` with the kernel mapper and frame allocator.
+///
+/// Returns `None` if `install_kernel_paging` has not been called yet.
+pub fn with_kernel_paging<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> Option<R> {
+    // Disable interrupts while holding the lock to avoid deadlock
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut paging = KERNEL_PAGING.lock();
+        let (mapper, frame_allocator) = paging.as_mut()?;
+        Some(f(mapper, frame_allocator))
+    })
+}
+
+/// Like `with_kernel_paging`, but returns `None` instead of spinning when the
+/// kernel paging state is already locked.
+///
+/// Used from contexts that may run while the lock is held, e.g. the heap
+/// allocator when code inside `with_kernel_paging` allocates.
+pub(crate) fn try_with_kernel_paging<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> Option<R> {
+    let mut paging = KERNEL_PAGING.try_lock()?;
+    let (mapper, frame_allocator) = paging.as_mut()?;
+    Some(f(mapper, frame_allocator))
+}
+
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
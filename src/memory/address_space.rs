@@ -0,0 +1,505 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{
+    instructions::tlb::Pcid,
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PageTableIndex, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{cow, mapping, vmm, BootInfoFrameAllocator};
+
+/// Start of the user part of every address space
+pub const USER_START: u64 = 0x_6000_0000_0000;
+/// End (exclusive) of the user part of every address space
+pub const USER_END: u64 = 0x_7000_0000_0000;
+
+/// Level 4 entries owned by an address space, all others are the kernel's
+pub(super) const USER_P4_ENTRIES: core::ops::Range<usize> =
+    (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// Number of PCIDs, PCID 0 is used by the kernel page table
+const PCIDS: usize = 4096;
+/// Stored as TLB generation of address spaces whose PCID has to be flushed
+/// on the next activation
+const NEEDS_FLUSH: u64 = u64::MAX;
+
+/// One bit per PCID that is in use
+static USED_PCIDS: [AtomicU64; PCIDS / 64] = {
+    let mut words = [const { AtomicU64::new(0) }; PCIDS / 64];
+    words[0] = AtomicU64::new(1);
+    words
+};
+
+/// Flags of the page tables in the user part; leaf entries decide about
+/// the actual access rights
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    /// The page is outside of `USER_START..USER_END`
+    NotUserAddress,
+    /// The page is already mapped
+    AlreadyMapped,
+    /// The page is not mapped
+    NotMapped,
+    /// No frame was left for the page or its page tables
+    OutOfMemory,
+    /// A frame has too many copy-on-write owners to be shared again
+    TooManyOwners,
+}
+
+/// Run `f` with the kernel mapper and frame allocator.
+fn with_paging<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> R {
+    super::with_kernel_paging(f).expect("kernel paging not installed")
+}
+
+/// Returns whether PCIDs are used, enabling them on the first call.
+fn pcid_enabled() -> bool {
+    static ENABLED: spin::Once<bool> = spin::Once::new();
+    *ENABLED.call_once(|| {
+        let supported = core::arch::x86_64::__cpuid(1).ecx & (1 << 17) != 0;
+        // CR4.PCIDE may only be set while the current PCID is 0
+        if supported && Cr3::read_raw().1 == 0 {
+            unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+            true
+        } else {
+            false
+        }
+    })
+}
+
+/// Returns a PCID that is not in use, or `None` if all are.
+///
+/// The TLB may still hold entries of a previous user of the PCID.
+fn allocate_pcid() -> Option<Pcid> {
+    if !pcid_enabled() {
+        return None;
+    }
+    for (index, word) in USED_PCIDS.iter().enumerate() {
+        let mut used = word.load(Ordering::Relaxed);
+        while used != u64::MAX {
+            let bit = used.trailing_ones();
+            match word.compare_exchange_weak(
+                used,
+                used | 1 << bit,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Pcid::new((index * 64) as u16 + bit as u16).ok(),
+                Err(current) => used = current,
+            }
+        }
+    }
+    None
+}
+
+/// Return `pcid` to the unused PCIDs.
+fn free_pcid(pcid: Pcid) {
+    let value = usize::from(pcid.value());
+    USED_PCIDS[value / 64].fetch_and(!(1 << (value % 64)), Ordering::Relaxed);
+}
+
+/// Switch back to the kernel page table.
+pub fn activate_kernel() {
+    let level_4_frame = with_paging(|mapper, _| level_4_frame(mapper));
+    unsafe {
+        if pcid_enabled() {
+            Cr3::write_pcid(level_4_frame, Pcid::new(0).unwrap());
+        } else {
+            Cr3::write(level_4_frame, Cr3Flags::empty());
+        }
+    }
+}
+
+/// Returns the frame of the level 4 table of the given mapper.
+fn level_4_frame(mapper: &OffsetPageTable) -> PhysFrame {
+    let virt = VirtAddr::from_ptr(mapper.level_4_table() as *const PageTable);
+    PhysFrame::containing_address(PhysAddr::new(virt - mapper.phys_offset()))
+}
+
+/// Free the page table in `frame` and everything mapped below it.
+unsafe fn free_table(frame: PhysFrame, level: u8, frame_allocator: &mut BootInfoFrameAllocator) {
+    let table = &mut *(super::physical_memory_offset() + frame.start_address().as_u64())
+        .as_mut_ptr::<PageTable>();
+    for entry in table.iter_mut().filter(|e| !e.is_unused()) {
+        let child = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            free_table(child, level - 1, frame_allocator);
+        } else if !cow::unshare(child) {
+            frame_allocator.deallocate_frame(child);
+        }
+        entry.set_unused();
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+/// An isolated address space for running user programs.
+///
+/// The level 4 entries outside of `USER_START..USER_END` point to the
+/// kernel's page tables, so the kernel stays mapped in every address space.
+/// The user part is owned by the address space: all of its frames and page
+/// tables are freed when it is dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    pcid: Option<Pcid>,
+    /// Kernel TLB generation the entries tagged with `pcid` are current
+    /// with, or `NEEDS_FLUSH`
+    tlb_generation: AtomicU64,
+}
+
+impl AddressSpace {
+    /// Create an address space with an empty user part.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        with_paging(|kernel, frame_allocator| {
+            // make sure every kernel level 4 entry that may ever be used
+            // exists, since later entries would not be shared
+            for region_start in (vmm::KERNEL_WINDOW_START..vmm::KERNEL_WINDOW_END).step_by(1 << 39)
+            {
+                let index = VirtAddr::new(region_start).p4_index();
+                let entry = &mut kernel.level_4_table_mut()[index];
+                if entry.is_unused() {
+                    let frame: PhysFrame = frame_allocator
+                        .allocate_frame()
+                        .ok_or(AddressSpaceError::OutOfMemory)?;
+                    unsafe { zero_frame(frame) };
+                    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+                }
+            }
+
+            let level_4_frame = frame_allocator
+                .allocate_frame()
+                .ok_or(AddressSpaceError::OutOfMemory)?;
+            let level_4_table = unsafe { zero_frame(level_4_frame) };
+            for (index, entry) in kernel.level_4_table().iter().enumerate() {
+                if !USER_P4_ENTRIES.contains(&index) {
+                    level_4_table[index] = entry.clone();
+                }
+            }
+            Ok(Self {
+                level_4_frame,
+                pcid: allocate_pcid(),
+                // the PCID may have been used before
+                tlb_generation: AtomicU64::new(NEEDS_FLUSH),
+            })
+        })
+    }
+
+    /// Returns the frame of the level 4 table, the value loaded into CR3.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns the PCID tagging the TLB entries of this address space, if
+    /// PCIDs are used.
+    pub fn pcid(&self) -> Option<Pcid> {
+        self.pcid
+    }
+
+    /// Returns whether this address space is the active one.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Make this address space the active one by loading it into CR3.
+    ///
+    /// # Safety
+    ///
+    /// The caller must not rely on user mappings of the previously active
+    /// address space anymore.
+    pub unsafe fn activate(&self) {
+        match self.pcid {
+            Some(pcid) => {
+                // the entries tagged with the PCID are kept if nothing
+                // changed since they were last flushed
+                let generation = mapping::kernel_tlb_generation();
+                if self.tlb_generation.swap(generation, Ordering::Relaxed) == generation {
+                    Cr3::write_pcid_no_flush(self.level_4_frame, pcid);
+                } else {
+                    Cr3::write_pcid(self.level_4_frame, pcid);
+                }
+            }
+            // all PCIDs are in use: run under PCID 0 and flush it, as
+            // `activate_kernel` does
+            None => Cr3::write(self.level_4_frame, Cr3Flags::empty()),
+        }
+    }
+
+    /// Map the user page `page` to a newly allocated, zeroed frame.
+    ///
+    /// `USER_ACCESSIBLE` and `PRESENT` are added to `flags`.
+    pub fn map(&mut self, page: Page, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+        check_user_page(page)?;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        with_paging(|_, frame_allocator| {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(AddressSpaceError::OutOfMemory)?;
+            unsafe {
+                zero_frame(frame);
+                match self.mapper().map_to_with_table_flags(
+                    page,
+                    frame,
+                    flags,
+                    USER_TABLE_FLAGS,
+                    frame_allocator,
+                ) {
+                    // flushing only matters if the address space is active,
+                    // and the page was not mapped before
+                    Ok(flush) => flush.ignore(),
+                    Err(err) => {
+                        frame_allocator.deallocate_frame(frame);
+                        return Err(match err {
+                            MapToError::PageAlreadyMapped(_) => AddressSpaceError::AlreadyMapped,
+                            _ => AddressSpaceError::OutOfMemory,
+                        });
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Unmap the user page `page` and free its frame.
+    pub fn unmap(&mut self, page: Page) -> Result<(), AddressSpaceError> {
+        check_user_page(page)?;
+        with_paging(|_, frame_allocator| {
+            let (frame, flush) = unsafe { self.mapper() }
+                .unmap(page)
+                .map_err(|_| AddressSpaceError::NotMapped)?;
+            if self.is_active() {
+                flush.flush();
+            } else {
+                // the TLB may still hold the page for the PCID
+                flush.ignore();
+                self.tlb_generation.store(NEEDS_FLUSH, Ordering::Relaxed);
+            }
+            if !cow::unshare(frame) {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            Ok(())
+        })
+    }
+
+    /// Create a copy of this address space that shares all user frames.
+    ///
+    /// Writable pages become read-only copy-on-write pages in both address
+    /// spaces; the first write to such a page copies the frame (see
+    /// `handle_cow_fault`). Writes by the kernel only fault with write
+    /// protection enabled (see `protect::protect_kernel`). Fails with
+    /// `TooManyOwners` if a frame is already shared by `u16::MAX` other
+    /// owners.
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, AddressSpaceError> {
+        // allocates, so it has to happen before kernel paging is locked
+        cow::init();
+        let child = AddressSpace::new()?;
+
+        let result = with_paging(|_, frame_allocator| {
+            let mut mapper = unsafe { self.mapper() };
+            let level_4_table = mapper.level_4_table_mut();
+            let mut child_mapper = unsafe { child.mapper() };
+            for p4 in USER_P4_ENTRIES {
+                let Some(level_3_table) = (unsafe { super::next_table(&level_4_table[p4]) }) else {
+                    continue;
+                };
+                for (p3, entry) in level_3_table.iter().enumerate() {
+                    let Some(level_2_table) = (unsafe { super::next_table(entry) }) else {
+                        continue;
+                    };
+                    for (p2, entry) in level_2_table.iter().enumerate() {
+                        let Some(level_1_table) = (unsafe { super::next_table(entry) }) else {
+                            continue;
+                        };
+                        for (p1, entry) in level_1_table.iter_mut().enumerate() {
+                            if entry.is_unused() {
+                                continue;
+                            }
+                            let mut flags = entry.flags();
+                            if flags.contains(PageTableFlags::WRITABLE) {
+                                flags = (flags - PageTableFlags::WRITABLE) | cow::COW;
+                                entry.set_flags(flags);
+                            }
+                            let frame = PhysFrame::containing_address(entry.addr());
+                            let page = Page::from_page_table_indices(
+                                PageTableIndex::new(p4 as u16),
+                                PageTableIndex::new(p3 as u16),
+                                PageTableIndex::new(p2 as u16),
+                                PageTableIndex::new(p1 as u16),
+                            );
+                            unsafe {
+                                child_mapper
+                                    .map_to_with_table_flags(
+                                        page,
+                                        frame,
+                                        flags,
+                                        USER_TABLE_FLAGS,
+                                        frame_allocator,
+                                    )
+                                    .map_err(|_| AddressSpaceError::OutOfMemory)?
+                                    .ignore();
+                            }
+                            // only count the child as an owner once it maps
+                            // the frame
+                            if !cow::share(frame) {
+                                child_mapper
+                                    .unmap(page)
+                                    .expect("child mapping vanished")
+                                    .1
+                                    .ignore();
+                                return Err(AddressSpaceError::TooManyOwners);
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(())
+        });
+        // pages that were writable are read-only now
+        if self.is_active() {
+            x86_64::instructions::tlb::flush_all();
+        } else {
+            self.tlb_generation.store(NEEDS_FLUSH, Ordering::Relaxed);
+        }
+        result.map(|()| child)
+    }
+
+    /// Translate `addr` in this address space, which need not be active.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match unsafe { self.mapper() }.translate(addr) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        }
+    }
+
+    /// Returns a mapper for the page tables of this address space.
+    ///
+    /// # Safety
+    ///
+    /// Only one mapper of the address space may be used at a time.
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        let offset = super::physical_memory_offset();
+        let level_4_table =
+            (offset + self.level_4_frame.start_address().as_u64()).as_mut_ptr::<PageTable>();
+        OffsetPageTable::new(&mut *level_4_table, offset)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // switch away before freeing the tables: the processor only walks
+        // the paging structures of the active address space
+        if self.is_active() {
+            activate_kernel();
+        }
+        let offset = super::physical_memory_offset();
+        with_paging(|_, frame_allocator| unsafe {
+            let level_4_table = &mut *(offset + self.level_4_frame.start_address().as_u64())
+                .as_mut_ptr::<PageTable>();
+            for index in USER_P4_ENTRIES {
+                let entry = &mut level_4_table[index];
+                if !entry.is_unused() {
+                    free_table(
+                        PhysFrame::containing_address(entry.addr()),
+                        3,
+                        frame_allocator,
+                    );
+                    entry.set_unused();
+                }
+            }
+            frame_allocator.deallocate_frame(self.level_4_frame);
+        });
+        // the next user of the PCID flushes it on activation
+        if let Some(pcid) = self.pcid {
+            free_pcid(pcid);
+        }
+    }
+}
+
+/// Resolve a write fault at `addr` to a copy-on-write page of the active
+/// address space.
+///
+/// Copies the frame if it is still shared, otherwise just makes the page
+/// writable again. Returns `false` if `addr` is not in a copy-on-write page.
+pub(super) fn handle_cow_fault(addr: VirtAddr) -> bool {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    if check_user_page(page).is_err() {
+        return false;
+    }
+    super::try_with_kernel_paging(|_, frame_allocator| {
+        let offset = super::physical_memory_offset();
+        let level_4_table = unsafe {
+            &mut *(offset + Cr3::read().0.start_address().as_u64()).as_mut_ptr::<PageTable>()
+        };
+        let mut mapper = unsafe { OffsetPageTable::new(level_4_table, offset) };
+
+        let (frame, flags) = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } if flags.contains(cow::COW) => (frame, flags),
+            _ => return false,
+        };
+        let flags = (flags - cow::COW) | PageTableFlags::WRITABLE;
+
+        if !cow::is_shared(frame) {
+            // all other owners are gone, take the frame over
+            return match unsafe { mapper.update_flags(page, flags) } {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                Err(_) => false,
+            };
+        }
+
+        let copy: PhysFrame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        unsafe {
+            let src = (offset + frame.start_address().as_u64()).as_ptr::<u8>();
+            let dst = (offset + copy.start_address().as_u64()).as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(src, dst, 4096);
+            // the page tables exist, so remapping needs no new frames
+            mapper.unmap(page).expect("cow page vanished").1.ignore();
+            mapper
+                .map_to_with_table_flags(page, copy, flags, USER_TABLE_FLAGS, frame_allocator)
+                .expect("remapping cow page failed")
+                .flush();
+        }
+        cow::unshare(frame);
+        true
+    })
+    .unwrap_or(false)
+}
+
+fn check_user_page(page: Page) -> Result<(), AddressSpaceError> {
+    let addr = page.start_address().as_u64();
+    if (USER_START..USER_END).contains(&addr) {
+        Ok(())
+    } else {
+        Err(AddressSpaceError::NotUserAddress)
+    }
+}
+
+/// Zero the given frame and return it as a page table.
+unsafe fn zero_frame(frame: PhysFrame<Size4KiB>) -> &'static mut PageTable {
+    let table = &mut *(super::physical_memory_offset() + frame.start_address().as_u64())
+        .as_mut_ptr::<PageTable>();
+    table.zero();
+    table
+}
@@ -0,0 +1,389 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Order of the largest block (4 KiB << 18 = 1 GiB)
+pub const MAX_ORDER: usize = 18;
+
+/// Sentinel stored in a free block when it is the first or last entry of its
+/// list
+const NO_BLOCK: u64 = u64::MAX;
+
+/// Size in bytes of a block of the given order
+const fn block_size(order: usize) -> u64 {
+    Size4KiB::SIZE << order
+}
+
+/// Links of a free block, stored in its first 16 bytes
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// Returns the number of bitmap words for the blocks of every order in
+/// `start..end`, and the offset of the words of each order.
+fn bitmap_layout(start: u64, end: u64) -> (usize, [usize; MAX_ORDER + 1]) {
+    let mut offsets = [0; MAX_ORDER + 1];
+    let mut words = 0;
+    for (order, offset) in offsets.iter_mut().enumerate() {
+        let shift = 12 + order;
+        let blocks = ((end - 1) >> shift) - (start >> shift) + 1;
+        *offset = words;
+        words += blocks.div_ceil(64) as usize;
+    }
+    (words, offsets)
+}
+
+/// A buddy-system physical frame allocator.
+///
+/// Free memory is kept as naturally aligned blocks of `4 KiB << order` bytes,
+/// one free list per order. Allocating splits larger blocks, freeing merges a
+/// block with its buddy whenever the buddy is free too. This allows handing
+/// out 2 MiB and 1 GiB frames as well as contiguous runs of 4 KiB frames.
+///
+/// The free lists are intrusive and doubly linked: the links of each free
+/// block are stored in its first 16 bytes, accessed through the physical
+/// memory mapping. A bitmap with one bit per block of every order tells
+/// whether a buddy is free, so merging takes constant time per order.
+pub struct BuddyFrameAllocator {
+    /// Virtual address at which the physical memory is mapped
+    physical_memory_offset: VirtAddr,
+    /// Start address of the first free block of every order
+    free_lists: [u64; MAX_ORDER + 1],
+    /// Set bits mark the blocks that are in a free list
+    bitmap: &'static mut [u64],
+    /// Index of the first word of every order in `bitmap`
+    bitmap_offsets: [usize; MAX_ORDER + 1],
+    /// Physical range covered by the bitmap
+    start: u64,
+    end: u64,
+    free_frames: u64,
+}
+
+impl BuddyFrameAllocator {
+    /// Create a buddy allocator managing all `Usable` regions of the memory map.
+    ///
+    /// The bitmap is placed at the start of the first usable region large
+    /// enough to hold it.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the memory map is valid, that all frames
+    /// marked as `Usable` are really unused and that the complete physical
+    /// memory is mapped at `physical_memory_offset`. It must not be used
+    /// together with another allocator that hands out the same frames.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| {
+                    let start = PhysAddr::new(r.range.start_addr()).align_up(Size4KiB::SIZE);
+                    let end = PhysAddr::new(r.range.end_addr()).align_down(Size4KiB::SIZE);
+                    (start.as_u64(), end.as_u64())
+                })
+                .filter(|(start, end)| start < end)
+        };
+        let start = usable().map(|(start, _)| start).min().unwrap_or(0);
+        let end = usable().map(|(_, end)| end).max().unwrap_or(Size4KiB::SIZE);
+
+        let (words, _) = bitmap_layout(start, end);
+        let bitmap_size = (words as u64 * 8).next_multiple_of(Size4KiB::SIZE);
+        let (bitmap_start, _) = usable()
+            .find(|(start, end)| end - start >= bitmap_size)
+            .expect("no usable region can hold the buddy bitmap");
+        let bitmap = core::slice::from_raw_parts_mut(
+            (physical_memory_offset + bitmap_start).as_mut_ptr(),
+            words,
+        );
+
+        let mut allocator = Self::new(physical_memory_offset, start, end, bitmap);
+        for (region_start, region_end) in usable() {
+            let region_start = if region_start == bitmap_start {
+                region_start + bitmap_size
+            } else {
+                region_start
+            };
+            allocator.free_range(region_start, region_end);
+        }
+        allocator
+    }
+
+    /// Create an allocator without free memory for the physical range
+    /// `start..end`, keeping its state in `bitmap`.
+    ///
+    /// # Safety
+    ///
+    /// `bitmap` must have the length `bitmap_layout` returns for the range.
+    unsafe fn new(
+        physical_memory_offset: VirtAddr,
+        start: u64,
+        end: u64,
+        bitmap: &'static mut [u64],
+    ) -> Self {
+        let (words, bitmap_offsets) = bitmap_layout(start, end);
+        assert_eq!(bitmap.len(), words);
+        bitmap.fill(0);
+        Self {
+            physical_memory_offset,
+            free_lists: [NO_BLOCK; MAX_ORDER + 1],
+            bitmap,
+            bitmap_offsets,
+            start,
+            end,
+            free_frames: 0,
+        }
+    }
+
+    /// Allocate a naturally aligned block of `4 KiB << order` bytes.
+    pub fn allocate_order(&mut self, order: usize) -> Option<PhysAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NO_BLOCK)?;
+        let addr = self.free_lists[current];
+        unsafe { self.remove(current, addr) };
+        // split the block, returning the upper halves to the lower orders
+        while current > order {
+            current -= 1;
+            unsafe { self.push(current, addr + block_size(current)) };
+        }
+        self.free_frames -= 1 << order;
+        Some(PhysAddr::new(addr))
+    }
+
+    /// Free a block previously returned by `allocate_order` with the same order.
+    ///
+    /// # Safety
+    ///
+    /// The block must have been allocated from this allocator and must not be
+    /// in use anymore.
+    pub unsafe fn deallocate_order(&mut self, addr: PhysAddr, order: usize) {
+        self.free_frames += 1 << order;
+        let mut addr = addr.as_u64();
+        let mut order = order;
+        // merge with the buddy as long as it is free
+        while order < MAX_ORDER && self.is_free(order, addr ^ block_size(order)) {
+            self.remove(order, addr ^ block_size(order));
+            addr &= !block_size(order);
+            order += 1;
+        }
+        self.push(order, addr);
+    }
+
+    /// Allocate `count` physically contiguous 4 KiB frames.
+    ///
+    /// The run starts at a block of the next power-of-two size; the unused
+    /// tail of that block is returned to the allocator immediately.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        if count == 0 {
+            return None;
+        }
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        let start = self.allocate_order(order)?;
+        let end = start + count as u64 * Size4KiB::SIZE;
+        unsafe { self.free_range(end.as_u64(), (start + block_size(order)).as_u64()) };
+        Some(PhysFrame::range(
+            PhysFrame::containing_address(start),
+            PhysFrame::containing_address(end),
+        ))
+    }
+
+    /// Free a run returned by `allocate_contiguous`.
+    ///
+    /// # Safety
+    ///
+    /// The frames must have been allocated from this allocator and must not be
+    /// in use anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        self.free_range(
+            range.start.start_address().as_u64(),
+            range.end.start_address().as_u64(),
+        );
+    }
+
+    /// Returns the number of free 4 KiB frames.
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    /// Returns the number of free bytes.
+    pub fn free_bytes(&self) -> u64 {
+        self.free_frames * Size4KiB::SIZE
+    }
+
+    /// Free the page aligned range `start..end` as maximal aligned blocks.
+    unsafe fn free_range(&mut self, mut start: u64, end: u64) {
+        while start < end {
+            let mut order = (start.trailing_zeros() as usize - 12).min(MAX_ORDER);
+            while start + block_size(order) > end {
+                order -= 1;
+            }
+            self.deallocate_order(PhysAddr::new(start), order);
+            start += block_size(order);
+        }
+    }
+
+    /// Returns a pointer to the links stored in the given block.
+    fn link(&self, addr: u64) -> *mut FreeBlock {
+        // wrapping, as the tests place fake physical addresses above the
+        // memory backing them
+        self.physical_memory_offset.as_u64().wrapping_add(addr) as *mut FreeBlock
+    }
+
+    /// Returns the bitmap word and mask of the block at `addr`.
+    fn bit(&self, order: usize, addr: u64) -> (usize, u64) {
+        let shift = 12 + order;
+        let index = ((addr >> shift) - (self.start >> shift)) as usize;
+        (self.bitmap_offsets[order] + index / 64, 1 << (index % 64))
+    }
+
+    /// Returns whether the block at `addr` is in the free list of `order`.
+    fn is_free(&self, order: usize, addr: u64) -> bool {
+        if addr < self.start || addr + block_size(order) > self.end {
+            return false;
+        }
+        let (word, mask) = self.bit(order, addr);
+        self.bitmap[word] & mask != 0
+    }
+
+    /// Push a free block to the front of the list of the given order.
+    unsafe fn push(&mut self, order: usize, addr: u64) {
+        let head = self.free_lists[order];
+        self.link(addr).write(FreeBlock {
+            next: head,
+            prev: NO_BLOCK,
+        });
+        if head != NO_BLOCK {
+            (*self.link(head)).prev = addr;
+        }
+        self.free_lists[order] = addr;
+        let (word, mask) = self.bit(order, addr);
+        self.bitmap[word] |= mask;
+    }
+
+    /// Remove the given free block from the list of the given order.
+    unsafe fn remove(&mut self, order: usize, addr: u64) {
+        let FreeBlock { next, prev } = self.link(addr).read();
+        match prev {
+            NO_BLOCK => self.free_lists[order] = next,
+            prev => (*self.link(prev)).next = next,
+        }
+        if next != NO_BLOCK {
+            (*self.link(next)).prev = prev;
+        }
+        let (word, mask) = self.bit(order, addr);
+        self.bitmap[word] &= !mask;
+    }
+}
+
+/// Returns the buddy order of frames of the given page size.
+fn order_of<S: PageSize>() -> usize {
+    (S::SIZE / Size4KiB::SIZE).trailing_zeros() as usize
+}
+
+unsafe impl<S: PageSize> FrameAllocator<S> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        self.allocate_order(order_of::<S>())
+            .map(PhysFrame::containing_address)
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        self.deallocate_order(frame.start_address(), order_of::<S>());
+    }
+}
+
+/// Run `f` with an allocator managing the fake physical range `start..end`,
+/// which is backed by a static buffer starting at `start`.
+///
+/// Only the links at the start of free blocks are stored in the buffer, so
+/// it must cover those of the blocks the test creates.
+#[cfg(test)]
+fn with_test_allocator(start: u64, end: u64, f: impl FnOnce(&mut BuddyFrameAllocator)) {
+    const TEST_MEMORY_SIZE: usize = 64 * 1024;
+    #[repr(align(4096))]
+    struct TestMemory([u8; TEST_MEMORY_SIZE]);
+    static mut TEST_MEMORY: TestMemory = TestMemory([0; TEST_MEMORY_SIZE]);
+    const TEST_BITMAP_WORDS: usize = 8 * 1024 + 32;
+    static mut TEST_BITMAP: [u64; TEST_BITMAP_WORDS] = [0; TEST_BITMAP_WORDS];
+
+    let (words, _) = bitmap_layout(start, end);
+    let memory = unsafe { core::ptr::addr_of_mut!(TEST_MEMORY.0) } as u64;
+    let offset = VirtAddr::new_truncate(memory.wrapping_sub(start));
+    assert!(words <= TEST_BITMAP_WORDS);
+    let mut allocator = unsafe {
+        let bitmap =
+            core::slice::from_raw_parts_mut(core::ptr::addr_of_mut!(TEST_BITMAP).cast(), words);
+        BuddyFrameAllocator::new(offset, start, end, bitmap)
+    };
+    unsafe { allocator.free_range(start, end) };
+    f(&mut allocator);
+}
+
+#[test_case]
+fn test_split_and_merge() {
+    with_test_allocator(0, 64 * 1024, |allocator| {
+        assert_eq!(allocator.free_lists[4], 0);
+        let a = allocator.allocate_order(0).unwrap();
+        let b = allocator.allocate_order(0).unwrap();
+        assert_eq!((a.as_u64(), b.as_u64()), (0, 4096));
+        // the rest of the 64 KiB block is split into 8, 16 and 32 KiB blocks
+        assert_eq!(allocator.free_bytes(), 56 * 1024);
+        assert_eq!(allocator.free_lists[4], NO_BLOCK);
+        assert_eq!(allocator.free_lists[3], 32 * 1024);
+
+        unsafe {
+            allocator.deallocate_order(a, 0);
+            allocator.deallocate_order(b, 0);
+        }
+        assert_eq!(allocator.free_bytes(), 64 * 1024);
+        assert_eq!(allocator.free_lists[4], 0);
+        assert!((0..4).all(|order| allocator.free_lists[order] == NO_BLOCK));
+    });
+}
+
+#[test_case]
+fn test_huge_frames_are_naturally_aligned() {
+    use x86_64::structures::paging::{Size1GiB, Size2MiB};
+
+    // a single 4 KiB frame precedes the aligned block in both cases
+    const MIB: u64 = 1024 * 1024;
+    with_test_allocator(2 * MIB - 4096, 4 * MIB, |allocator| {
+        let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address().as_u64(), 2 * MIB);
+        assert!(FrameAllocator::<Size2MiB>::allocate_frame(allocator).is_none());
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.free_bytes(), 2 * MIB + 4096);
+    });
+    with_test_allocator(1024 * MIB - 4096, 2048 * MIB, |allocator| {
+        let frame: PhysFrame<Size1GiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address().as_u64(), 1024 * MIB);
+        assert_eq!(allocator.free_bytes(), 4096);
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.free_lists[MAX_ORDER], 1024 * MIB);
+    });
+}
+
+#[test_case]
+fn test_allocate_contiguous_returns_tail() {
+    with_test_allocator(0, 64 * 1024, |allocator| {
+        let run = allocator.allocate_contiguous(3).unwrap();
+        assert_eq!(run.start.start_address().as_u64(), 0);
+        assert_eq!(run.count(), 3);
+        // the fourth frame of the 16 KiB block is free again
+        assert_eq!(allocator.free_bytes(), 52 * 1024);
+        assert_eq!(allocator.allocate_order(0).unwrap().as_u64(), 3 * 4096);
+
+        unsafe {
+            allocator.deallocate_order(PhysAddr::new(3 * 4096), 0);
+            allocator.deallocate_contiguous(run);
+        }
+        assert_eq!(allocator.free_lists[4], 0);
+    });
+}
@@ -0,0 +1,72 @@
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use bootloader::bootinfo::MemoryRegionType;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+
+/// Available page table bit marking a read-only page as copy-on-write
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Number of additional owners of every physical frame, indexed by frame
+/// number; 0 means the frame is owned exclusively
+static SHARE_COUNTS: spin::Once<Box<[AtomicU16]>> = spin::Once::new();
+
+/// Allocate the share counts if necessary.
+///
+/// Only frames up to the end of the highest usable region get a count, as
+/// only those are handed out by the frame allocator; reserved regions can
+/// lie far above the installed memory.
+///
+/// Allocates on the heap, so it must not be called while the kernel paging
+/// state is locked (the heap might need to grow).
+pub(super) fn init() {
+    SHARE_COUNTS.call_once(|| {
+        let usable_memory_end = super::with_kernel_paging(|_, frame_allocator| {
+            frame_allocator
+                .memory_map()
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| r.range.end_addr())
+                .max()
+                .unwrap_or(0)
+        })
+        .expect("kernel paging not installed");
+        (0..usable_memory_end / 4096)
+            .map(|_| AtomicU16::new(0))
+            .collect()
+    });
+}
+
+fn count(frame: PhysFrame) -> Option<&'static AtomicU16> {
+    let index = (frame.start_address().as_u64() / 4096) as usize;
+    SHARE_COUNTS.get()?.get(index)
+}
+
+/// Record an additional owner of `frame`.
+///
+/// Returns `false` if the count of `frame` is exhausted; the owner is not
+/// recorded then.
+#[must_use]
+pub(super) fn share(frame: PhysFrame) -> bool {
+    count(frame)
+        .expect("share counts not initialized")
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| c.checked_add(1))
+        .is_ok()
+}
+
+/// Drop one owner of `frame`.
+///
+/// Returns `true` if other owners remain, `false` if the caller was the only
+/// owner and may free the frame.
+pub(super) fn unshare(frame: PhysFrame) -> bool {
+    count(frame).is_some_and(|count| {
+        count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| c.checked_sub(1))
+            .is_ok()
+    })
+}
+
+/// Returns whether `frame` has more than one owner.
+pub(super) fn is_shared(frame: PhysFrame) -> bool {
+    count(frame).is_some_and(|count| count.load(Ordering::Relaxed) > 0)
+}
@@ -0,0 +1,178 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+use super::MappedSize;
+use crate::{
+    allocator::{try_alloc::try_reserve, AllocError},
+    serial_println,
+};
+
+/// Flags that the CPU updates on access and that are ignored when comparing
+const VOLATILE_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+
+/// A run of virtually and physically contiguous pages with the same size and
+/// flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingRange {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    /// Length in bytes
+    pub len: u64,
+    pub size: MappedSize,
+    /// Leaf entry flags without `ACCESSED` and `DIRTY`
+    pub flags: PageTableFlags,
+}
+
+impl MappingRange {
+    /// Try to extend the range by the given page, returns `false` if the page
+    /// does not directly follow the range.
+    fn extend(&mut self, next: &MappingRange) -> bool {
+        let follows = self.virt.as_u64().checked_add(self.len) == Some(next.virt.as_u64())
+            && self.phys + self.len == next.phys
+            && self.size == next.size
+            && self.flags == next.flags;
+        if follows {
+            self.len += next.len;
+        }
+        follows
+    }
+}
+
+impl fmt::Display for MappingRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag, set, unset| {
+            if self.flags.contains(flag) {
+                set
+            } else {
+                unset
+            }
+        };
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x}-{:#014x} {:>4} r{}{}{}{}",
+            self.virt.as_u64(),
+            self.virt.as_u64() + self.len,
+            self.phys.as_u64(),
+            self.phys.as_u64() + self.len,
+            match self.size {
+                MappedSize::Page4KiB => "4K",
+                MappedSize::Page2MiB => "2M",
+                MappedSize::Page1GiB => "1G",
+            },
+            flag(PageTableFlags::WRITABLE, 'w', '-'),
+            flag(PageTableFlags::USER_ACCESSIBLE, 'u', '-'),
+            flag(PageTableFlags::NO_EXECUTE, '-', 'x'),
+            flag(PageTableFlags::GLOBAL, 'g', '-'),
+        )
+    }
+}
+
+/// Call `f` for every leaf entry of the page table at `table_addr`.
+///
+/// `base` is the virtual address mapped by the first entry of the table.
+fn walk_table(table_addr: PhysAddr, level: u8, base: u64, f: &mut impl FnMut(MappingRange)) {
+    let virt = super::physical_memory_offset() + table_addr.as_u64();
+    let table = unsafe { &*virt.as_ptr::<PageTable>() };
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let addr = base | (index as u64 * entry_size);
+        let size = match level {
+            3 if flags.contains(PageTableFlags::HUGE_PAGE) => MappedSize::Page1GiB,
+            2 if flags.contains(PageTableFlags::HUGE_PAGE) => MappedSize::Page2MiB,
+            1 => MappedSize::Page4KiB,
+            _ => {
+                walk_table(entry.addr(), level - 1, addr, f);
+                continue;
+            }
+        };
+        f(MappingRange {
+            virt: VirtAddr::new_truncate(addr),
+            phys: entry.addr(),
+            len: entry_size,
+            size,
+            flags: flags - VOLATILE_FLAGS,
+        });
+    }
+}
+
+/// Call `f` for every maximal range of the active page table, in ascending
+/// virtual address order.
+fn for_each_range(mut f: impl FnMut(&MappingRange)) {
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut current: Option<MappingRange> = None;
+    walk_table(level_4_table_frame.start_address(), 4, 0, &mut |page| {
+        let extended = current.as_mut().is_some_and(|range| range.extend(&page));
+        if !extended {
+            if let Some(range) = current.replace(page) {
+                f(&range);
+            }
+        }
+    });
+    if let Some(range) = current {
+        f(&range);
+    }
+}
+
+/// Print all mappings of the active page table to the serial port.
+///
+/// Does not allocate, so it can be used while debugging the heap.
+pub fn dump_page_tables() {
+    serial_println!("page tables (CR3 = {:?}):", Cr3::read().0);
+    for_each_range(|range| serial_println!("  {}", range));
+}
+
+/// The mappings of a page table at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageTableSnapshot {
+    pub ranges: Vec<MappingRange>,
+}
+
+/// Record the mappings of the active page table.
+///
+/// The ranges are counted first and room for them is reserved before the
+/// walk, as growing the heap changes the page tables being walked.
+pub fn snapshot() -> Result<PageTableSnapshot, AllocError> {
+    let mut ranges = Vec::new();
+    loop {
+        let mut count = 0;
+        for_each_range(|_| count += 1);
+        ranges.clear();
+        try_reserve(&mut ranges, count)?;
+
+        // reserving may have added ranges; start over if they don't fit
+        let mut complete = true;
+        for_each_range(|range| {
+            if ranges.len() < ranges.capacity() {
+                ranges.push(*range);
+            } else {
+                complete = false;
+            }
+        });
+        if complete {
+            return Ok(PageTableSnapshot { ranges });
+        }
+    }
+}
+
+/// Print the ranges that were removed (`-`) or added (`+`) between two
+/// snapshots to the serial port.
+pub fn print_diff(old: &PageTableSnapshot, new: &PageTableSnapshot) {
+    serial_println!("page table diff:");
+    for range in old.ranges.iter().filter(|r| !new.ranges.contains(r)) {
+        serial_println!("- {}", range);
+    }
+    for range in new.ranges.iter().filter(|r| !old.ranges.contains(r)) {
+        serial_println!("+ {}", range);
+    }
+}
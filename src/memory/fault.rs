@@ -0,0 +1,38 @@
+use x86_64::{structures::idt::PageFaultErrorCode, VirtAddr};
+
+use super::{
+    address_space, lazy, stack,
+    vmm::{self, RegionKind},
+};
+
+/// Outcome of `handle_page_fault`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultResolution {
+    /// The fault was fixed up, the faulting instruction can be retried
+    Resolved,
+    /// The fault hit the guard page of the kernel stack with the given id
+    StackOverflow(usize),
+    /// The fault cannot be handled
+    Fatal,
+}
+
+/// Try to resolve a page fault at `addr`.
+///
+/// Called by the page fault handler before it reports the fault.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> FaultResolution {
+    let not_present = !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    if let Some(id) = stack::guard_page_hit(addr) {
+        return FaultResolution::StackOverflow(id);
+    }
+    let write_to_present = error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE);
+    if write_to_present && address_space::handle_cow_fault(addr) {
+        return FaultResolution::Resolved;
+    }
+    match vmm::try_find(addr).map(|r| r.kind) {
+        Some(RegionKind::Lazy(flags)) if not_present && lazy::handle_fault(flags, addr) => {
+            FaultResolution::Resolved
+        }
+        _ => FaultResolution::Fatal,
+    }
+}
@@ -0,0 +1,69 @@
+use x86_64::{
+    structures::paging::{
+        mapper::TranslateResult, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+use super::vmm::{self, RegionKind, VirtRegion, VmmError};
+
+/// Reserve a lazily backed region of at least `size` bytes.
+///
+/// No memory is committed up front: the first access to each page faults and
+/// `handle_fault` maps a zeroed frame with `flags` (plus `PRESENT`, and
+/// `NO_EXECUTE` if enabled).
+pub fn reserve(size: u64, flags: PageTableFlags) -> Result<VirtRegion, VmmError> {
+    let flags = flags | PageTableFlags::PRESENT | super::protect::no_execute();
+    vmm::allocate(size, 4096, RegionKind::Lazy(flags))
+}
+
+/// Release a region returned by `reserve`, freeing all frames that were
+/// faulted in.
+///
+/// # Safety
+///
+/// No references into the region may be used afterwards.
+pub unsafe fn release(region: VirtRegion) -> Result<(), VmmError> {
+    if !matches!(region.kind, RegionKind::Lazy(_)) {
+        return Err(VmmError::NotFound);
+    }
+    // lazy regions are always mapped with 4 KiB pages
+    super::unmap_range(region.pages()).expect("lazy region contains a huge page");
+    vmm::release(region.start).map(|_| ())
+}
+
+/// Back the page containing `addr` of a lazy region with a zeroed frame.
+///
+/// Returns `false` if the page could not be mapped, e.g. because no frame is
+/// left or the kernel paging state is locked by the faulting code.
+pub(super) fn handle_fault(flags: PageTableFlags, addr: VirtAddr) -> bool {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    super::try_with_kernel_paging(|mapper, frame_allocator| {
+        // only missing pages are backed, anything else is a real fault
+        if !matches!(
+            mapper.translate(page.start_address()),
+            TranslateResult::NotMapped
+        ) {
+            return false;
+        }
+        let frame: PhysFrame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        unsafe {
+            let frame_ptr: *mut u8 =
+                (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+            frame_ptr.write_bytes(0, 4096);
+            match mapper.map_to(page, frame, flags, frame_allocator) {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    frame_allocator.deallocate_frame(frame);
+                    return false;
+                }
+            }
+        }
+        true
+    })
+    .unwrap_or(false)
+}
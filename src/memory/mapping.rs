@@ -0,0 +1,242 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{
+        mapper::{FlagUpdateError, UnmapError},
+        page::PageRange,
+        page_table::PageTableEntry,
+        FrameDeallocator, Mapper, OffsetPageTable, PageTable, PageTableFlags, PhysFrame,
+    },
+    VirtAddr,
+};
+
+use super::{address_space::USER_P4_ENTRIES, BootInfoFrameAllocator};
+
+/// Above this many pages, range operations reload CR3 once instead of
+/// invalidating every page with `invlpg`
+pub const FLUSH_ALL_THRESHOLD: usize = 32;
+
+/// Number of unlinked frames queued before the TLB is flushed and they are
+/// freed
+const PENDING_FRAMES: usize = 64;
+
+/// Incremented by every flush of removed or restricted kernel mappings
+static KERNEL_TLB_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of kernel TLB flushes so far.
+///
+/// A flush only reaches the TLB entries of the current PCID, so the entries
+/// kept for other PCIDs are stale once the generation changed.
+pub(super) fn kernel_tlb_generation() -> u64 {
+    KERNEL_TLB_GENERATION.load(Ordering::Relaxed)
+}
+
+/// Flush the whole TLB of the current PCID.
+fn flush_all() {
+    KERNEL_TLB_GENERATION.fetch_add(1, Ordering::Relaxed);
+    tlb::flush_all();
+}
+
+/// Flush the TLB entries of `pages`, batching large ranges into one flush.
+fn flush_range(pages: PageRange) {
+    if pages.count() > FLUSH_ALL_THRESHOLD {
+        flush_all();
+    } else {
+        KERNEL_TLB_GENERATION.fetch_add(1, Ordering::Relaxed);
+        pages.for_each(|page| tlb::flush(page.start_address()));
+    }
+}
+
+/// Frames unlinked from the page tables that wait for the TLB flush.
+///
+/// The frame allocator writes its free lists into freed frames, so a frame
+/// may only be freed once no TLB entry or cached paging structure can refer
+/// to it anymore.
+struct PendingFrames {
+    frames: [Option<PhysFrame>; PENDING_FRAMES],
+    len: usize,
+}
+
+impl PendingFrames {
+    const fn new() -> Self {
+        Self {
+            frames: [None; PENDING_FRAMES],
+            len: 0,
+        }
+    }
+
+    /// Queue `frame`, flushing the whole TLB and freeing the queued frames
+    /// first if the queue is full.
+    unsafe fn push(&mut self, frame: PhysFrame, frame_allocator: &mut BootInfoFrameAllocator) {
+        if self.len == PENDING_FRAMES {
+            flush_all();
+            self.free(frame_allocator);
+        }
+        self.frames[self.len] = Some(frame);
+        self.len += 1;
+    }
+
+    /// Free the queued frames.
+    ///
+    /// The TLB must have been flushed since the frames were unlinked.
+    unsafe fn free(&mut self, frame_allocator: &mut BootInfoFrameAllocator) {
+        for frame in self.frames[..self.len].iter_mut().filter_map(Option::take) {
+            frame_allocator.deallocate_frame(frame);
+        }
+        self.len = 0;
+    }
+}
+
+/// Unmap `pages` from the kernel page table and free the page tables that
+/// became empty.
+///
+/// Pages that are not mapped are skipped. If `free_frames` is set, the leaf
+/// frames are returned to the frame allocator. Returns the number of unmapped
+/// pages.
+pub(crate) fn unmap_pages(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    pages: PageRange,
+    free_frames: bool,
+) -> Result<usize, UnmapError> {
+    if pages.is_empty() {
+        return Ok(0);
+    }
+    let mut pending = PendingFrames::new();
+    let mut unmapped = 0;
+    let mut result = Ok(());
+    for page in pages {
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                // flushed below in one batch
+                flush.ignore();
+                if free_frames {
+                    unsafe { pending.push(frame, frame_allocator) };
+                }
+                unmapped += 1;
+            }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+    let tables_freed = unsafe {
+        free_empty_tables(
+            mapper.level_4_table_mut(),
+            &mut pending,
+            frame_allocator,
+            pages,
+        )
+    };
+    if tables_freed {
+        // the paging-structure caches may hold entries for the whole span
+        // of a freed table
+        flush_all();
+    } else {
+        flush_range(pages);
+    }
+    unsafe { pending.free(frame_allocator) };
+    result.map(|()| unmapped)
+}
+
+/// Unlink the page tables covering `pages` that map nothing anymore and
+/// queue their frames in `pending`.
+///
+/// P3 tables are only freed in the user part of the kernel page table:
+/// every address space shares the other level 4 entries, so their P3 tables
+/// must stay valid as long as any address space exists. Returns whether a
+/// table was unlinked.
+unsafe fn free_empty_tables(
+    level_4_table: &mut PageTable,
+    pending: &mut PendingFrames,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    pages: PageRange,
+) -> bool {
+    fn is_empty(table: &PageTable) -> bool {
+        table.iter().all(PageTableEntry::is_unused)
+    }
+
+    let mut freed = false;
+    let mut free = |entry: &mut PageTableEntry, frame_allocator: &mut BootInfoFrameAllocator| {
+        let frame = PhysFrame::containing_address(entry.addr());
+        entry.set_unused();
+        pending.push(frame, frame_allocator);
+        freed = true;
+    };
+
+    let first = pages.start.start_address().as_u64();
+    let last = (pages.end - 1).start_address().as_u64();
+    // walk every 1 GiB region (one P2 table) and 2 MiB region (one P1 table)
+    // touched by the range
+    for gib in (first >> 30)..=(last >> 30) {
+        let addr = VirtAddr::new_truncate(gib << 30);
+        let Some(level_3_table) = super::next_table(&level_4_table[addr.p4_index()]) else {
+            continue;
+        };
+        let Some(level_2_table) = super::next_table(&level_3_table[addr.p3_index()]) else {
+            continue;
+        };
+        for mib in (first >> 21).max(gib << 9)..=(last >> 21).min((gib << 9) + 511) {
+            let index = VirtAddr::new_truncate(mib << 21).p2_index();
+            if super::next_table(&level_2_table[index]).is_some_and(|t| is_empty(t)) {
+                free(&mut level_2_table[index], frame_allocator);
+            }
+        }
+        if is_empty(level_2_table) {
+            free(&mut level_3_table[addr.p3_index()], frame_allocator);
+        }
+        let p4_index = usize::from(addr.p4_index());
+        if USER_P4_ENTRIES.contains(&p4_index) && is_empty(level_3_table) {
+            free(&mut level_4_table[p4_index], frame_allocator);
+        }
+    }
+    freed
+}
+
+/// Unmap `pages` from the kernel page table.
+///
+/// The leaf frames and the page tables that became empty are returned to the
+/// frame allocator. Pages that are not mapped are skipped. Returns the number
+/// of unmapped pages.
+///
+/// # Safety
+///
+/// The pages must not be accessed anymore and their frames must not be
+/// referenced by other mappings.
+pub unsafe fn unmap_range(pages: PageRange) -> Result<usize, UnmapError> {
+    super::with_kernel_paging(|mapper, frame_allocator| {
+        unmap_pages(mapper, frame_allocator, pages, true)
+    })
+    .unwrap_or(Ok(0))
+}
+
+/// Replace the flags of all `pages` in the kernel page table.
+///
+/// Fails at the first page that is not mapped; the pages before it keep
+/// their new flags.
+///
+/// # Safety
+///
+/// Changing flags can break memory safety, e.g. by making memory that is
+/// still referenced inaccessible.
+pub unsafe fn remap_range(pages: PageRange, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    super::with_kernel_paging(|mapper, _| {
+        let mut result = Ok(());
+        for page in pages {
+            match mapper.update_flags(page, flags) {
+                // flushed below in one batch
+                Ok(flush) => flush.ignore(),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        flush_range(pages);
+        result
+    })
+    .unwrap_or(Err(FlagUpdateError::PageNotMapped))
+}
@@ -0,0 +1,216 @@
+use core::mem::{align_of, size_of};
+
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::Msr,
+    structures::paging::{Mapper, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::vmm::{self, RegionKind, VirtRegion, VmmError};
+
+/// The page attribute table MSR
+const IA32_PAT: u32 = 0x277;
+/// PAT memory type for write combining
+const PAT_WRITE_COMBINING: u64 = 0x01;
+/// PAT entry reprogrammed to write combining; selected by the PAT bit alone
+const WRITE_COMBINING_ENTRY: u64 = 4;
+/// The PAT bit of a 4 KiB page table entry, which shares its position with
+/// `HUGE_PAGE`
+const PAT_BIT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// Caching behaviour of a memory-mapped I/O region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Uncached, for device registers
+    Uncached,
+    /// Reads are cached, writes go straight to the device
+    WriteThrough,
+    /// Writes are buffered and combined, for framebuffers; falls back to
+    /// `Uncached` if the CPU has no PAT
+    WriteCombining,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioError {
+    /// No virtual range could be reserved
+    Vmm(VmmError),
+    /// No frame was left for the page tables
+    OutOfMemory,
+}
+
+/// Returns whether the CPU supports the page attribute table.
+fn pat_supported() -> bool {
+    core::arch::x86_64::__cpuid(1).edx & (1 << 16) != 0
+}
+
+/// Program the write combining PAT entry on first use.
+///
+/// Returns `false` if the CPU has no PAT.
+fn enable_write_combining() -> bool {
+    static ENABLED: spin::Once<bool> = spin::Once::new();
+
+    *ENABLED.call_once(|| {
+        if !pat_supported() {
+            return false;
+        }
+        let mut pat = Msr::new(IA32_PAT);
+        let shift = WRITE_COMBINING_ENTRY * 8;
+        unsafe {
+            // the entry is unused until now, so no mapping changes its type
+            let value = pat.read() & !(0xff << shift) | PAT_WRITE_COMBINING << shift;
+            pat.write(value);
+        }
+        tlb::flush_all();
+        true
+    })
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if enable_write_combining() => PAT_BIT,
+            CacheMode::WriteCombining => CacheMode::Uncached.flags(),
+        }
+    }
+}
+
+/// A mapping of device memory in the kernel window.
+///
+/// All accesses are volatile and bounds checked. The pages are unmapped when
+/// the handle is dropped; the device memory itself is never freed.
+#[derive(Debug)]
+pub struct MmioRegion {
+    region: VirtRegion,
+    phys: PhysAddr,
+    base: VirtAddr,
+    len: usize,
+    /// Flags of the page table entries
+    flags: PageTableFlags,
+}
+
+impl MmioRegion {
+    /// Returns the physical address the region was requested for.
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Returns the virtual address that `phys` is mapped at.
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    /// Returns the length in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a pointer to the `T` at `offset`.
+    ///
+    /// Panics if the value is not fully inside the region or misaligned.
+    pub fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset
+                .checked_add(size_of::<T>())
+                .is_some_and(|end| end <= self.len),
+            "mmio access at {:#x} out of bounds",
+            offset
+        );
+        let addr = self.base + offset as u64;
+        assert!(
+            addr.is_aligned(align_of::<T>() as u64),
+            "misaligned mmio access at {:#x}",
+            offset
+        );
+        addr.as_mut_ptr()
+    }
+
+    /// Read the `T` at `offset`.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Write `value` to `offset`.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        super::with_kernel_paging(|mapper, frame_allocator| {
+            if self.flags.contains(PAT_BIT) {
+                // `unmap` takes the PAT bit for a huge page and refuses
+                for page in self.region.pages() {
+                    if let Ok(flush) = unsafe { mapper.update_flags(page, self.flags - PAT_BIT) } {
+                        flush.ignore();
+                    }
+                }
+            }
+            super::mapping::unmap_pages(mapper, frame_allocator, self.region.pages(), false)
+        })
+        .unwrap_or(Ok(0))
+        .expect("mmio region contains a huge page");
+        vmm::release(self.region.start).expect("mmio region vanished");
+    }
+}
+
+/// Map `len` bytes of device memory at `phys` into the kernel window.
+pub fn map_mmio(
+    phys: PhysAddr,
+    len: usize,
+    cache_mode: CacheMode,
+) -> Result<MmioRegion, MmioError> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let offset = phys - first_frame.start_address();
+    let region =
+        vmm::allocate(offset + len as u64, 4096, RegionKind::Mmio).map_err(MmioError::Vmm)?;
+    let cache_flags = cache_mode.flags();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | super::protect::no_execute();
+    // from here on dropping the handle cleans up partially mapped regions
+    let mmio = MmioRegion {
+        region,
+        phys,
+        base: region.start + offset,
+        len,
+        flags: flags | cache_flags,
+    };
+
+    let mapped = super::with_kernel_paging(|mapper, frame_allocator| {
+        for (i, page) in region.pages().enumerate() {
+            let frame = first_frame + i as u64;
+            unsafe {
+                // the PAT bit cannot be passed to `map_to` because it
+                // doubles as the huge page flag, so it is set afterwards
+                mapper
+                    .map_to(
+                        page,
+                        frame,
+                        flags | (cache_flags - PAT_BIT),
+                        frame_allocator,
+                    )
+                    .map_err(|_| MmioError::OutOfMemory)?
+                    .ignore();
+                if cache_flags.contains(PAT_BIT) {
+                    mapper
+                        .update_flags(page, flags | cache_flags)
+                        .expect("mmio page vanished")
+                        .ignore();
+                }
+            }
+            tlb::flush(page.start_address());
+        }
+        Ok(())
+    });
+    match mapped {
+        Some(Ok(())) => Ok(mmio),
+        Some(Err(err)) => Err(err),
+        None => Err(MmioError::OutOfMemory),
+    }
+}
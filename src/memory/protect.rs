@@ -0,0 +1,85 @@
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+// Section boundaries defined in `linker.ld`, all page aligned
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// Returns `NO_EXECUTE` if no-execute protection is enabled, empty flags
+/// otherwise.
+///
+/// The NX bit is reserved (and faults) while `EFER.NXE` is clear, so data
+/// mappings must use this instead of `PageTableFlags::NO_EXECUTE`.
+pub fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Change the flags of all pages between two linker symbols.
+unsafe fn protect_section(
+    mapper: &mut OffsetPageTable,
+    start: *const u8,
+    end: *const u8,
+    flags: PageTableFlags,
+) {
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(start));
+    let end = Page::containing_address(VirtAddr::from_ptr(end));
+    for page in Page::range(start, end) {
+        mapper
+            .update_flags(page, flags)
+            .expect("kernel section not mapped with 4 KiB pages")
+            .flush();
+    }
+}
+
+/// Enable `EFER.NXE` and `CR0.WP` and remap the kernel sections: `.text`
+/// read-only and executable, `.rodata` read-only and NX, `.data`/`.bss`
+/// writable and NX.
+///
+/// Must be called before the heap is set up, so that it is mapped NX too.
+///
+/// # Safety
+///
+/// The mapper must map the active page table, which must map the kernel with
+/// 4 KiB pages.
+pub unsafe fn protect_kernel(mapper: &mut OffsetPageTable) {
+    use PageTableFlags as Flags;
+
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    // make the CPU honor read-only pages in kernel mode as well
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+
+    protect_section(
+        mapper,
+        &raw const __text_start,
+        &raw const __text_end,
+        Flags::PRESENT,
+    );
+    protect_section(
+        mapper,
+        &raw const __rodata_start,
+        &raw const __rodata_end,
+        Flags::PRESENT | Flags::NO_EXECUTE,
+    );
+    protect_section(
+        mapper,
+        &raw const __data_start,
+        &raw const __data_end,
+        Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE,
+    );
+}
@@ -0,0 +1,99 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, Mapper, PageTableFlags},
+    VirtAddr,
+};
+
+use super::vmm::{self, RegionKind, VirtRegion, VmmError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// No virtual range could be reserved for the stack
+    Vmm(VmmError),
+    /// No frame was left to back the stack
+    OutOfMemory,
+}
+
+/// A kernel stack with an unmapped guard page below it.
+///
+/// The stack pages are unmapped and their frames freed when the handle is
+/// dropped, so the stack must not be in use anymore by then.
+#[derive(Debug)]
+pub struct StackBounds {
+    id: usize,
+    /// The reserved range including the guard page
+    region: VirtRegion,
+}
+
+impl StackBounds {
+    /// Returns the id reported when the guard page is hit.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns the lowest usable address of the stack.
+    pub fn start(&self) -> VirtAddr {
+        self.region.start + 4096u64
+    }
+
+    /// Returns the initial stack pointer, the stack grows downwards.
+    pub fn end(&self) -> VirtAddr {
+        self.region.end()
+    }
+}
+
+impl Drop for StackBounds {
+    fn drop(&mut self) {
+        unsafe { super::unmap_range(self.region.pages()) }.expect("stack contains a huge page");
+        vmm::release(self.region.start).expect("stack region vanished");
+    }
+}
+
+/// Allocate a kernel stack of `pages` pages with a guard page below it.
+pub fn alloc_stack(pages: u64) -> Result<StackBounds, StackError> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let region =
+        vmm::allocate((pages + 1) * 4096, 4096, RegionKind::Stack(id)).map_err(StackError::Vmm)?;
+    // from here on dropping the handle cleans up partially mapped stacks
+    let stack = StackBounds { id, region };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | super::protect::no_execute();
+    let mapped = super::with_kernel_paging(|mapper, frame_allocator| {
+        for page in region.pages().skip(1) {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(StackError::OutOfMemory)?;
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    return Err(StackError::OutOfMemory);
+                }
+            }
+        }
+        Ok(())
+    });
+    match mapped {
+        Some(Ok(())) => Ok(stack),
+        Some(Err(err)) => Err(err),
+        None => Err(StackError::OutOfMemory),
+    }
+}
+
+/// Returns the id of the stack whose guard page contains `addr`, if any.
+///
+/// Safe to call from exception handlers: returns `None` instead of blocking
+/// when the region table is locked.
+pub fn guard_page_hit(addr: VirtAddr) -> Option<usize> {
+    match vmm::try_find(addr)? {
+        VirtRegion {
+            start,
+            kind: RegionKind::Stack(id),
+            ..
+        } if addr < start + 4096u64 => Some(id),
+        _ => None,
+    }
+}
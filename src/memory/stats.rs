@@ -0,0 +1,101 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+use crate::serial_println;
+
+/// Physical memory usage, see `stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// All memory listed in the memory map
+    pub total: u64,
+    /// Memory handed to the frame allocator (`Usable`)
+    pub usable: u64,
+    /// Memory the kernel can never use (`Reserved`, ACPI, `BadMemory`)
+    pub reserved: u64,
+    /// The kernel image and its boot stack (`Kernel`, `KernelStack`)
+    pub kernel: u64,
+    /// Page tables set up by the bootloader (`PageTable`)
+    pub page_tables: u64,
+    /// Everything else (`InUse`, `Bootloader`, `BootInfo`, `FrameZero`, ...)
+    pub other: u64,
+    /// Frames currently allocated by the kernel frame allocator
+    pub frames_allocated: u64,
+    /// Usable frames that are not allocated
+    pub frames_free: u64,
+}
+
+/// Returns the number of bytes of the given region type in the memory map.
+pub fn region_bytes(memory_map: &MemoryMap, region_type: MemoryRegionType) -> u64 {
+    memory_map
+        .iter()
+        .filter(|r| r.region_type == region_type)
+        .map(|r| r.range.end_addr() - r.range.start_addr())
+        .sum()
+}
+
+/// Returns the physical memory statistics.
+///
+/// Returns `None` if `install_kernel_paging` has not been called yet.
+pub fn stats() -> Option<MemoryStats> {
+    super::with_kernel_paging(|_, frame_allocator| {
+        use MemoryRegionType::*;
+
+        let memory_map = frame_allocator.memory_map();
+        let bytes = |types: &[MemoryRegionType]| -> u64 {
+            types.iter().map(|&t| region_bytes(memory_map, t)).sum()
+        };
+        let total = memory_map
+            .iter()
+            .map(|r| r.range.end_addr() - r.range.start_addr())
+            .sum();
+        let usable = bytes(&[Usable]);
+        let reserved = bytes(&[Reserved, AcpiReclaimable, AcpiNvs, BadMemory]);
+        let kernel = bytes(&[Kernel, KernelStack]);
+        let page_tables = bytes(&[PageTable]);
+        MemoryStats {
+            total,
+            usable,
+            reserved,
+            kernel,
+            page_tables,
+            other: total - usable - reserved - kernel - page_tables,
+            frames_allocated: frame_allocator.allocated_frames(),
+            frames_free: frame_allocator.free_frames(),
+        }
+    })
+}
+
+/// Print the memory map and the memory statistics to the serial port.
+pub fn print_memory_map() {
+    let Some(stats) = stats() else {
+        serial_println!("memory map: kernel paging not installed");
+        return;
+    };
+    let memory_map = super::with_kernel_paging(|_, frame_allocator| frame_allocator.memory_map())
+        .expect("kernel paging vanished");
+
+    serial_println!("memory map:");
+    for region in memory_map.iter() {
+        let (start, end) = (region.range.start_addr(), region.range.end_addr());
+        serial_println!(
+            "  {:#012x}-{:#012x} {:>8} KiB {:?}",
+            start,
+            end,
+            (end - start) / 1024,
+            region.region_type
+        );
+    }
+    serial_println!(
+        "total {} KiB, usable {} KiB, reserved {} KiB, kernel {} KiB, page tables {} KiB, other {} KiB",
+        stats.total / 1024,
+        stats.usable / 1024,
+        stats.reserved / 1024,
+        stats.kernel / 1024,
+        stats.page_tables / 1024,
+        stats.other / 1024
+    );
+    serial_println!(
+        "frames: {} allocated, {} free",
+        stats.frames_allocated,
+        stats.frames_free
+    );
+}
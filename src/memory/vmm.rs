@@ -0,0 +1,246 @@
+use core::fmt;
+
+use x86_64::{
+    structures::paging::{page::PageRange, Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// Start of the window in which `allocate` carves out kernel regions
+pub const KERNEL_WINDOW_START: u64 = 0x_5000_0000_0000;
+/// End (exclusive) of the window in which `allocate` carves out kernel regions
+pub const KERNEL_WINDOW_END: u64 = 0x_6000_0000_0000;
+
+/// Maximum number of regions the manager can track
+const MAX_REGIONS: usize = 128;
+
+/// What a kernel virtual region is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// The kernel heap
+    Heap,
+    /// A kernel stack with the given id, the lowest page is its guard page,
+    /// see `memory::stack`
+    Stack(usize),
+    /// Memory mapped device registers
+    Mmio,
+    /// The bootloader's mapping of the complete physical memory
+    PhysicalMemory,
+    /// Backed on first access with zeroed frames mapped with the given flags,
+    /// see `memory::lazy`
+    Lazy(PageTableFlags),
+    /// Any other reserved range
+    Other,
+}
+
+/// A page aligned range of kernel virtual memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRegion {
+    pub start: VirtAddr,
+    /// Size in bytes, a multiple of the page size
+    pub size: u64,
+    pub kind: RegionKind,
+}
+
+impl VirtRegion {
+    /// Returns the first address after the region.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Returns the pages covered by the region.
+    pub fn pages(&self) -> PageRange {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end()),
+        )
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start.as_u64() < end && start < self.end().as_u64()
+    }
+}
+
+impl fmt::Display for VirtRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#016x}-{:#016x} {:?}",
+            self.start.as_u64(),
+            self.end().as_u64(),
+            self.kind
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    /// The requested range overlaps an existing region
+    Overlap(VirtRegion),
+    /// The start or size is not page aligned, or the range is empty
+    InvalidRange,
+    /// No free range of the requested size is left in the kernel window
+    OutOfSpace,
+    /// The region table is full
+    TooManyRegions,
+    /// No region starts at the given address
+    NotFound,
+}
+
+/// Table of reserved regions, sorted by start address
+struct Vmm {
+    regions: [Option<VirtRegion>; MAX_REGIONS],
+    len: usize,
+}
+
+impl Vmm {
+    const fn new() -> Self {
+        Self {
+            regions: [None; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = VirtRegion> + '_ {
+        self.regions[..self.len].iter().flatten().copied()
+    }
+
+    fn insert(&mut self, region: VirtRegion) -> Result<VirtRegion, VmmError> {
+        let (start, end) = (region.start.as_u64(), region.end().as_u64());
+        if let Some(other) = self.iter().find(|r| r.overlaps(start, end)) {
+            return Err(VmmError::Overlap(other));
+        }
+        if self.len == MAX_REGIONS {
+            return Err(VmmError::TooManyRegions);
+        }
+        let index = self.iter().take_while(|r| r.start < region.start).count();
+        self.regions[index..=self.len].rotate_right(1);
+        self.regions[index] = Some(region);
+        self.len += 1;
+        Ok(region)
+    }
+
+    fn remove(&mut self, start: VirtAddr) -> Result<VirtRegion, VmmError> {
+        let index = self
+            .iter()
+            .position(|r| r.start == start)
+            .ok_or(VmmError::NotFound)?;
+        let region = self.regions[index].take();
+        self.regions[index..self.len].rotate_left(1);
+        self.len -= 1;
+        region.ok_or(VmmError::NotFound)
+    }
+
+    /// Find the lowest free range of `size` bytes aligned to `align` in the
+    /// kernel window.
+    fn find_free(&self, size: u64, align: u64) -> Option<u64> {
+        let mut candidate = align_up(KERNEL_WINDOW_START, align);
+        for region in self.iter() {
+            if region.end().as_u64() <= candidate {
+                continue;
+            }
+            if candidate.checked_add(size)? <= region.start.as_u64() {
+                break;
+            }
+            candidate = align_up(region.end().as_u64(), align);
+        }
+        (candidate.checked_add(size)? <= KERNEL_WINDOW_END).then_some(candidate)
+    }
+}
+
+static VMM: spin::Mutex<Vmm> = spin::Mutex::new(Vmm::new());
+
+fn with_vmm<R>(f: impl FnOnce(&mut Vmm) -> R) -> R {
+    // Disable interrupts while holding the lock to avoid deadlock
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut VMM.lock()))
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Reserve the fixed range `start..start + size`.
+///
+/// Fails if the range is not page aligned or overlaps an existing region.
+pub fn reserve(start: VirtAddr, size: u64, kind: RegionKind) -> Result<VirtRegion, VmmError> {
+    if size == 0 || !start.is_aligned(Size4KiB::SIZE) || !size.is_multiple_of(Size4KiB::SIZE) {
+        return Err(VmmError::InvalidRange);
+    }
+    VirtAddr::try_new(start.as_u64() + size - 1).map_err(|_| VmmError::InvalidRange)?;
+    with_vmm(|vmm| vmm.insert(VirtRegion { start, size, kind }))
+}
+
+/// Reserve a free range of at least `size` bytes in the kernel window.
+///
+/// The size is rounded up to whole pages and the start is aligned to `align`,
+/// which must be a power of two (anything below the page size means page
+/// alignment).
+pub fn allocate(size: u64, align: u64, kind: RegionKind) -> Result<VirtRegion, VmmError> {
+    if size == 0 || !align.is_power_of_two() {
+        return Err(VmmError::InvalidRange);
+    }
+    let size = align_up(size, Size4KiB::SIZE);
+    let align = align.max(Size4KiB::SIZE);
+    with_vmm(|vmm| {
+        let start = vmm.find_free(size, align).ok_or(VmmError::OutOfSpace)?;
+        vmm.insert(VirtRegion {
+            start: VirtAddr::new(start),
+            size,
+            kind,
+        })
+    })
+}
+
+/// Release the region starting at `start`.
+///
+/// This only updates the bookkeeping; unmapping the pages is up to the owner.
+pub fn release(start: VirtAddr) -> Result<VirtRegion, VmmError> {
+    with_vmm(|vmm| vmm.remove(start))
+}
+
+/// Returns the region containing `addr`, if any.
+pub fn find(addr: VirtAddr) -> Option<VirtRegion> {
+    with_vmm(|vmm| vmm.iter().find(|r| r.contains(addr)))
+}
+
+/// Like `find`, but returns `None` instead of spinning when the region table
+/// is locked, for use from exception handlers.
+pub(crate) fn try_find(addr: VirtAddr) -> Option<VirtRegion> {
+    VMM.try_lock()?.iter().find(|r| r.contains(addr))
+}
+
+/// Call `f` for every region in ascending address order.
+///
+/// `f` runs with the region table locked and must not call back into this
+/// module.
+pub fn for_each_region(mut f: impl FnMut(&VirtRegion)) {
+    with_vmm(|vmm| vmm.iter().for_each(|r| f(&r)))
+}
+
+#[test_case]
+fn test_allocate_is_aligned_and_disjoint() {
+    let a = allocate(3 * 4096, 0x20_0000, RegionKind::Other).unwrap();
+    let b = allocate(4096, 4096, RegionKind::Other).unwrap();
+    assert!(a.start.is_aligned(0x20_0000u64));
+    assert_eq!(a.size, 3 * 4096);
+    assert!(!a.overlaps(b.start.as_u64(), b.end().as_u64()));
+    assert_eq!(find(b.start + 100u64), Some(b));
+    release(a.start).unwrap();
+    release(b.start).unwrap();
+    assert_eq!(find(b.start), None);
+}
+
+#[test_case]
+fn test_reserve_refuses_overlap() {
+    let a = allocate(2 * 4096, 4096, RegionKind::Other).unwrap();
+    let overlapping = reserve(a.start + 4096u64, 2 * 4096, RegionKind::Mmio);
+    assert_eq!(overlapping, Err(VmmError::Overlap(a)));
+    assert_eq!(
+        reserve(a.start + 1u64, 4096, RegionKind::Mmio),
+        Err(VmmError::InvalidRange)
+    );
+    release(a.start).unwrap();
+}
@@ -25,6 +25,19 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Pass every byte waiting in the receive buffer of the serial port to `f`,
+/// which must not print to the serial port.
+pub(crate) fn receive_pending(mut f: impl FnMut(u8)) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL1.lock();
+        while let Ok(byte) = serial.try_receive() {
+            f(byte);
+        }
+    });
+}
+
 /// Prints to the host through the serial interface
 #[macro_export]
 macro_rules! serial_print {
@@ -1,9 +1,35 @@
-use core::task::{Context, Poll, Waker};
+use core::{
+    alloc::Layout,
+    fmt,
+    future::Future,
+    task::{Context, Poll, Waker},
+};
 
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
 use crossbeam_queue::ArrayQueue;
 
-use super::{Task, TaskId};
+use super::{try_array_queue, Task, TaskId};
+use crate::allocator::AllocError;
+
+/// Number of task ids the ready queue holds
+const TASK_QUEUE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The task or its waker could not be allocated
+    OutOfMemory(AllocError),
+    /// The queue of ready tasks is full
+    QueueFull,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnError::OutOfMemory(err) => write!(f, "{}", err),
+            SpawnError::QueueFull => write!(f, "the queue of ready tasks is full"),
+        }
+    }
+}
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
@@ -14,11 +40,21 @@ pub struct Executor {
 
 impl Executor {
     pub fn new() -> Self {
-        Self {
+        Self::try_new().expect("failed to allocate the task queue")
+    }
+
+    /// Like `new`, but returns an error if the task queue cannot be
+    /// allocated.
+    pub fn try_new() -> Result<Self, AllocError> {
+        let task_queue = try_array_queue(TASK_QUEUE_SIZE)?;
+        let task_queue = Arc::try_new(task_queue).map_err(|_| AllocError {
+            layout: Layout::new::<ArrayQueue<TaskId>>(),
+        })?;
+        Ok(Self {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue,
             waker_cache: BTreeMap::new(),
-        }
+        })
     }
 
     pub fn spawn(&mut self, task: Task) {
@@ -29,6 +65,26 @@ impl Executor {
         self.task_queue.push(task_id).expect("queue full");
     }
 
+    /// Spawn `future` as a new task, returning an error instead of panicking
+    /// if it cannot be allocated or queued.
+    ///
+    /// The task and its waker are allocated up front; only inserting them
+    /// may still allocate a new node of the task maps.
+    pub fn try_spawn(
+        &mut self,
+        future: impl Future<Output = ()> + 'static,
+    ) -> Result<(), SpawnError> {
+        if self.task_queue.is_full() {
+            return Err(SpawnError::QueueFull);
+        }
+        let task = Task::try_new(future).map_err(SpawnError::OutOfMemory)?;
+        let waker = TaskWaker::try_new(task.id, self.task_queue.clone())
+            .map_err(SpawnError::OutOfMemory)?;
+        self.waker_cache.insert(task.id, waker);
+        self.spawn(task);
+        Ok(())
+    }
+
     fn run_ready_tasks(&mut self) {
         let Self {
             tasks,
@@ -92,6 +148,18 @@ impl TaskWaker {
             task_queue,
         }))
     }
+
+    /// Like `new`, but returns an error if the waker cannot be allocated.
+    fn try_new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Result<Waker, AllocError> {
+        let waker = Arc::try_new(TaskWaker {
+            task_id,
+            task_queue,
+        })
+        .map_err(|_| AllocError {
+            layout: Layout::new::<TaskWaker>(),
+        })?;
+        Ok(Waker::from(waker))
+    }
 }
 
 impl Wake for TaskWaker {
@@ -5,7 +5,11 @@ use crossbeam_queue::ArrayQueue;
 use futures_util::{task::AtomicWaker, Stream, StreamExt};
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
-use crate::{print, println};
+use super::try_array_queue;
+use crate::{allocator::AllocError, print, println};
+
+/// Number of scancodes buffered until the stream is polled
+const SCANCODE_QUEUE_SIZE: usize = 100;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
@@ -28,10 +32,17 @@ pub struct ScancodeStream {
 
 impl ScancodeStream {
     pub fn new() -> Self {
+        Self::try_new().expect("failed to allocate the scancode queue")
+    }
+
+    /// Like `new`, but returns an error if the scancode queue cannot be
+    /// allocated.
+    pub fn try_new() -> Result<Self, AllocError> {
+        let queue = try_array_queue(SCANCODE_QUEUE_SIZE)?;
         SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
+            .try_init_once(|| queue)
             .expect("ScancodeStream::new should only be called once");
-        Self { _private: () }
+        Ok(Self { _private: () })
     }
 }
 
@@ -60,7 +71,13 @@ impl Stream for ScancodeStream {
 }
 
 pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
+    let mut scancodes = match ScancodeStream::try_new() {
+        Ok(scancodes) => scancodes,
+        Err(err) => {
+            println!("keyboard input disabled: {}", err);
+            return;
+        }
+    };
     let mut keyboard = Keyboard::new(
         ScancodeSet1::new(),
         layouts::Us104Key,
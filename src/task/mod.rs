@@ -1,14 +1,21 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use core::{
+    cell::UnsafeCell,
     future::Future,
+    mem::MaybeUninit,
     pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
     task::{Context, Poll},
 };
 
+use crossbeam_queue::ArrayQueue;
+
+use crate::allocator::{try_box, try_vec_with_capacity, AllocError};
+
 pub mod executor;
 pub mod keyboard;
 pub mod simple_executor;
+
 pub struct Task {
     future: Pin<Box<dyn Future<Output = ()>>>,
     id: TaskId,
@@ -22,6 +29,16 @@ impl Task {
         }
     }
 
+    /// Like `new`, but returns an error instead of panicking if the future
+    /// cannot be moved to the heap.
+    pub fn try_new(future: impl Future<Output = ()> + 'static) -> Result<Self, AllocError> {
+        let future: Box<dyn Future<Output = ()>> = try_box(future)?;
+        Ok(Self {
+            future: Box::into_pin(future),
+            id: TaskId::new(),
+        })
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
@@ -36,3 +53,15 @@ impl TaskId {
         Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
 }
+
+/// Create an `ArrayQueue` with room for `capacity` elements, returning an
+/// error instead of panicking if its buffer cannot be allocated.
+///
+/// `ArrayQueue::new` allocates infallibly, so a buffer with the layout of
+/// its slots is allocated and freed first to make sure the heap can hold it.
+fn try_array_queue<T>(capacity: usize) -> Result<ArrayQueue<T>, AllocError> {
+    // a slot is a stamp and the value
+    let probe: Vec<(AtomicUsize, UnsafeCell<MaybeUninit<T>>)> = try_vec_with_capacity(capacity)?;
+    drop(probe);
+    Ok(ArrayQueue::new(capacity))
+}
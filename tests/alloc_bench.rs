@@ -0,0 +1,291 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    hint::black_box,
+    panic::PanicInfo,
+    ptr,
+};
+
+use blog_os::{
+    allocator::{
+        self, bump::BumpAllocator, external::ExternalAllocator,
+        fixed_size_block::FixedSizeBlockAllocator, linked_list::LinkedListAllocator,
+        magazine::Magazines, Locked,
+    },
+    bench::Bench,
+    memory::{self, BootInfoFrameAllocator},
+    serial_println,
+};
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+/// Size of the private heap of every benchmarked backend, large enough that
+/// none of them has to grow its heap
+const BENCH_HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
+
+static BUMP: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+static LINKED_LIST: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+static FIXED_BLOCK: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static MAGAZINES: Magazines<Locked<FixedSizeBlockAllocator>> =
+    Magazines::new(Locked::new(FixedSizeBlockAllocator::new()));
+static EXTERNAL: Locked<ExternalAllocator> = Locked::new(ExternalAllocator::new());
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    unsafe {
+        allocator::init_heap(&mut mapper, &mut frame_allocator).unwrap();
+    }
+    memory::install_kernel_paging(mapper, frame_allocator);
+
+    // the backends are benchmarked directly, so the global allocator only
+    // provides their heaps
+    unsafe {
+        BUMP.lock().init(private_heap(), BENCH_HEAP_SIZE);
+        LINKED_LIST.lock().init(private_heap(), BENCH_HEAP_SIZE);
+        FIXED_BLOCK.lock().init(private_heap(), BENCH_HEAP_SIZE);
+        MAGAZINES.lock().init(private_heap(), BENCH_HEAP_SIZE);
+        EXTERNAL.lock().init(private_heap(), BENCH_HEAP_SIZE);
+    }
+
+    serial_println!("global allocator backend: {}", allocator::BACKEND);
+    test_main();
+
+    loop {}
+}
+
+/// Leak a `BENCH_HEAP_SIZE` region of the global heap and return its start.
+fn private_heap() -> usize {
+    Box::leak(vec![0u8; BENCH_HEAP_SIZE].into_boxed_slice()).as_mut_ptr() as usize
+}
+
+/// A xorshift generator, so every run sees the same "random" sequence
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Allocate `layout` from `allocator`, panicking if it is exhausted.
+fn allocate(allocator: &impl GlobalAlloc, layout: Layout) -> *mut u8 {
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert!(!ptr.is_null(), "benchmark heap exhausted");
+    ptr
+}
+
+fn bytes(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+fn small_boxes(allocator: &impl GlobalAlloc) {
+    let layout = Layout::new::<u64>();
+    for i in 0..1000u64 {
+        let ptr = allocate(allocator, layout).cast::<u64>();
+        unsafe {
+            ptr.write(i);
+            black_box(ptr);
+            allocator.dealloc(ptr.cast(), layout);
+        }
+    }
+}
+
+fn small_boxes_live(allocator: &impl GlobalAlloc) {
+    let layout = Layout::new::<u64>();
+    let mut boxes = [ptr::null_mut::<u8>(); 1000];
+    for (i, slot) in boxes.iter_mut().enumerate() {
+        *slot = allocate(allocator, layout);
+        unsafe { slot.cast::<u64>().write(i as u64) };
+    }
+    black_box(&boxes);
+    for ptr in boxes {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+}
+
+fn vec_growth(allocator: &impl GlobalAlloc) {
+    // grow like a `Vec<u32>` pushing 100 000 elements
+    let mut capacity = 4;
+    let mut layout = Layout::array::<u32>(capacity).unwrap();
+    let mut ptr = allocate(allocator, layout).cast::<u32>();
+    for i in 0..100_000u32 {
+        if i as usize == capacity {
+            capacity *= 2;
+            let new_size = capacity * core::mem::size_of::<u32>();
+            ptr = unsafe { allocator.realloc(ptr.cast(), layout, new_size) }.cast();
+            assert!(!ptr.is_null(), "benchmark heap exhausted");
+            layout = Layout::array::<u32>(capacity).unwrap();
+        }
+        unsafe { ptr.add(i as usize).write(i) };
+    }
+    black_box(ptr);
+    unsafe { allocator.dealloc(ptr.cast(), layout) };
+}
+
+fn random_mix(allocator: &impl GlobalAlloc) {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut slots: [Option<(*mut u8, Layout)>; 64] = [None; 64];
+    for _ in 0..2000 {
+        let slot = &mut slots[rng.next() as usize % 64];
+        match slot.take() {
+            Some((ptr, layout)) => unsafe { allocator.dealloc(ptr, layout) },
+            None => {
+                let layout = bytes(8 + rng.next() as usize % 2040);
+                *slot = Some((allocate(allocator, layout), layout));
+            }
+        }
+    }
+    black_box(&slots);
+    for (ptr, layout) in slots.into_iter().flatten() {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+}
+
+fn fragmentation(allocator: &impl GlobalAlloc) {
+    // interleave small and large blocks, free the large ones and refill the
+    // holes with blocks that do not quite fit
+    let (small_layout, large_layout, larger_layout) = (bytes(24), bytes(1000), bytes(1100));
+    let mut small = [ptr::null_mut::<u8>(); 256];
+    let mut large = [ptr::null_mut::<u8>(); 256];
+    for (s, l) in small.iter_mut().zip(large.iter_mut()) {
+        *s = allocate(allocator, small_layout);
+        *l = allocate(allocator, large_layout);
+    }
+    for l in large.iter_mut() {
+        unsafe { allocator.dealloc(*l, large_layout) };
+        *l = ptr::null_mut();
+    }
+    for l in large.iter_mut() {
+        *l = allocate(allocator, larger_layout);
+    }
+    black_box((&small, &large));
+    for (s, l) in small.into_iter().zip(large) {
+        unsafe {
+            allocator.dealloc(s, small_layout);
+            allocator.dealloc(l, larger_layout);
+        }
+    }
+}
+
+#[test_case]
+static SMALL_BOXES_BUMP: Bench = Bench::new("small boxes / bump", || small_boxes(&BUMP));
+#[test_case]
+static SMALL_BOXES_LINKED_LIST: Bench =
+    Bench::new("small boxes / linked-list", || small_boxes(&LINKED_LIST));
+#[test_case]
+static SMALL_BOXES_FIXED_BLOCK: Bench =
+    Bench::new("small boxes / fixed-block", || small_boxes(&FIXED_BLOCK));
+#[test_case]
+static SMALL_BOXES_MAGAZINES: Bench = Bench::new("small boxes / fixed-block + magazines", || {
+    small_boxes(&MAGAZINES)
+});
+#[test_case]
+static SMALL_BOXES_EXTERNAL: Bench =
+    Bench::new("small boxes / external", || small_boxes(&EXTERNAL));
+
+#[test_case]
+static SMALL_BOXES_LIVE_BUMP: Bench =
+    Bench::new("small boxes, all live / bump", || small_boxes_live(&BUMP));
+#[test_case]
+static SMALL_BOXES_LIVE_LINKED_LIST: Bench =
+    Bench::new("small boxes, all live / linked-list", || {
+        small_boxes_live(&LINKED_LIST)
+    });
+#[test_case]
+static SMALL_BOXES_LIVE_FIXED_BLOCK: Bench =
+    Bench::new("small boxes, all live / fixed-block", || {
+        small_boxes_live(&FIXED_BLOCK)
+    });
+#[test_case]
+static SMALL_BOXES_LIVE_MAGAZINES: Bench =
+    Bench::new("small boxes, all live / fixed-block + magazines", || {
+        small_boxes_live(&MAGAZINES)
+    });
+#[test_case]
+static SMALL_BOXES_LIVE_EXTERNAL: Bench = Bench::new("small boxes, all live / external", || {
+    small_boxes_live(&EXTERNAL)
+});
+
+#[test_case]
+static VEC_GROWTH_BUMP: Bench = Bench::new("large vec growth / bump", || vec_growth(&BUMP));
+#[test_case]
+static VEC_GROWTH_LINKED_LIST: Bench = Bench::new("large vec growth / linked-list", || {
+    vec_growth(&LINKED_LIST)
+});
+#[test_case]
+static VEC_GROWTH_FIXED_BLOCK: Bench = Bench::new("large vec growth / fixed-block", || {
+    vec_growth(&FIXED_BLOCK)
+});
+#[test_case]
+static VEC_GROWTH_MAGAZINES: Bench =
+    Bench::new("large vec growth / fixed-block + magazines", || {
+        vec_growth(&MAGAZINES)
+    });
+#[test_case]
+static VEC_GROWTH_EXTERNAL: Bench =
+    Bench::new("large vec growth / external", || vec_growth(&EXTERNAL));
+
+#[test_case]
+static RANDOM_MIX_BUMP: Bench = Bench::new("random alloc/free mix / bump", || random_mix(&BUMP));
+#[test_case]
+static RANDOM_MIX_LINKED_LIST: Bench = Bench::new("random alloc/free mix / linked-list", || {
+    random_mix(&LINKED_LIST)
+});
+#[test_case]
+static RANDOM_MIX_FIXED_BLOCK: Bench = Bench::new("random alloc/free mix / fixed-block", || {
+    random_mix(&FIXED_BLOCK)
+});
+#[test_case]
+static RANDOM_MIX_MAGAZINES: Bench =
+    Bench::new("random alloc/free mix / fixed-block + magazines", || {
+        random_mix(&MAGAZINES)
+    });
+#[test_case]
+static RANDOM_MIX_EXTERNAL: Bench =
+    Bench::new("random alloc/free mix / external", || random_mix(&EXTERNAL));
+
+#[test_case]
+static FRAGMENTATION_BUMP: Bench =
+    Bench::new("fragmentation / bump", || fragmentation(&BUMP)).iterations(5);
+#[test_case]
+static FRAGMENTATION_LINKED_LIST: Bench = Bench::new("fragmentation / linked-list", || {
+    fragmentation(&LINKED_LIST)
+})
+.iterations(5);
+#[test_case]
+static FRAGMENTATION_FIXED_BLOCK: Bench = Bench::new("fragmentation / fixed-block", || {
+    fragmentation(&FIXED_BLOCK)
+})
+.iterations(5);
+#[test_case]
+static FRAGMENTATION_MAGAZINES: Bench =
+    Bench::new("fragmentation / fixed-block + magazines", || {
+        fragmentation(&MAGAZINES)
+    })
+    .iterations(5);
+#[test_case]
+static FRAGMENTATION_EXTERNAL: Bench =
+    Bench::new("fragmentation / external", || fragmentation(&EXTERNAL)).iterations(5);
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{fmt::Write, panic::PanicInfo};
+
+use blog_os::{
+    allocator::{self, HEAP_SIZE},
+    exit_qemu,
+    memory::{self, BootInfoFrameAllocator},
+    serial_print, serial_println, QemuExitCode,
+};
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+/// Checks that formatted output starts with `expected`, without allocating
+struct PrefixMatcher {
+    expected: &'static str,
+    matched: usize,
+    mismatch: bool,
+}
+
+impl Write for PrefixMatcher {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let rest = &self.expected[self.matched..];
+        let len = rest.len().min(s.len());
+        if rest.as_bytes()[..len] != s.as_bytes()[..len] {
+            self.mismatch = true;
+        }
+        self.matched += len;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut matcher = PrefixMatcher {
+        expected: "allocation error",
+        matched: 0,
+        mismatch: false,
+    };
+    let _ = write!(matcher, "{}", info.message());
+    if matcher.mismatch || matcher.matched < matcher.expected.len() {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    } else {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    loop {}
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    unsafe {
+        allocator::init_heap(&mut mapper, &mut frame_allocator).unwrap();
+    }
+    memory::install_kernel_paging(mapper, frame_allocator);
+
+    out_of_memory_panics();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+fn out_of_memory_panics() {
+    serial_print!("alloc_oom::out_of_memory_panics...\t");
+    // keep the heap from growing, so the allocation below cannot succeed
+    allocator::set_heap_limit(0);
+    let v = Vec::<u8>::with_capacity(2 * HEAP_SIZE);
+    serial_println!("allocated {} bytes", v.capacity());
+}
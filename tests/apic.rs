@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use blog_os::{
+    allocator,
+    interrupts::{self, apic},
+    memory::{self, BootInfoFrameAllocator},
+};
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    unsafe {
+        allocator::init_heap(&mut mapper, &mut frame_allocator).unwrap();
+    }
+    memory::install_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[test_case]
+fn apic_replaces_pics() {
+    // QEMU emulates an APIC and describes it in the MADT
+    assert_eq!(apic::init(), Ok(()));
+    assert!(apic::local_apic().is_some());
+    // a second call keeps the running configuration
+    assert_eq!(apic::init(), Ok(()));
+}
+
+#[test_case]
+fn timer_routed_through_io_apic() {
+    assert!(apic::local_apic().is_some());
+    let start = interrupts::ticks();
+    while interrupts::ticks() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+}
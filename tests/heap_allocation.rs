@@ -9,7 +9,7 @@ use core::panic::PanicInfo;
 
 use alloc::boxed::Box;
 use alloc::{vec, vec::Vec};
-use blog_os::allocator::HEAP_SIZE;
+use blog_os::allocator::{heap_size, HEAP_SIZE};
 use blog_os::{memory::BootInfoFrameAllocator, println};
 use bootloader::{entry_point, BootInfo};
 use x86_64::VirtAddr;
@@ -27,11 +27,13 @@ fn main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { blog_os::memory::init(phys_mem_offset) };
 
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
 
     unsafe {
         blog_os::allocator::init_heap(&mut mapper, &mut frame_allocator).unwrap();
     }
+    blog_os::memory::install_kernel_paging(mapper, frame_allocator);
 
     test_main();
 
@@ -67,3 +69,139 @@ fn many_boxes_long() {
     println!("long_lived = {}", *long_lived);
     // assert_eq!(*long_lived, 1);
 }
+
+#[test_case]
+fn heap_grows_beyond_initial_size() {
+    let a = vec![1u8; 4 * HEAP_SIZE];
+    assert_eq!(a.iter().map(|&x| x as usize).sum::<usize>(), 4 * HEAP_SIZE);
+    assert!(heap_size() > 4 * HEAP_SIZE);
+}
+
+#[test_case]
+fn kmem_cache_reuses_objects() {
+    use blog_os::allocator::slab::KmemCache;
+
+    let cache = KmemCache::<[u64; 5]>::new("test-objects").with_poisoning();
+    let a = cache.alloc([1; 5]).unwrap();
+    let addr = &*a as *const [u64; 5];
+    assert_eq!(*a, [1; 5]);
+    drop(a);
+
+    // the freed object is handed out again and was not modified meanwhile
+    let b = cache.alloc([2; 5]).unwrap();
+    assert_eq!(&*b as *const [u64; 5], addr);
+    let stats = cache.stats();
+    assert_eq!(stats.object_size, 40);
+    assert_eq!(stats.objects_in_use, 1);
+    assert_eq!((stats.allocations, stats.frees), (2, 1));
+    assert_eq!(stats.slabs, 1);
+
+    assert_eq!(cache.shrink(), 0);
+    drop(b);
+    assert_eq!(cache.shrink(), 4096);
+    assert_eq!(cache.stats().objects_total, 0);
+}
+
+#[test_case]
+fn kmem_cache_runs_constructor() {
+    use blog_os::allocator::slab::KmemCache;
+
+    let cache = KmemCache::new("test-vecs").with_constructor(|| Vec::<u32>::with_capacity(4));
+    let mut objects: Vec<_> = (0..200)
+        .map(|_| cache.alloc_constructed().unwrap())
+        .collect();
+    objects[7].push(7);
+    assert!(objects.iter().all(|v| v.capacity() >= 4));
+    assert!(cache.stats().slabs > 1);
+    objects.clear();
+    assert_eq!(cache.stats().objects_in_use, 0);
+}
+
+#[test_case]
+fn stats_track_live_allocations() {
+    use blog_os::allocator;
+
+    let before = allocator::stats();
+    let a = Box::new([0u8; 100]);
+    let after = allocator::stats();
+    assert_eq!(after.bytes_in_use, before.bytes_in_use + 100);
+    assert_eq!(after.allocations, before.allocations + 1);
+    // 100 bytes fall into the 128 byte class
+    assert_eq!(after.live_per_class[4], before.live_per_class[4] + 1);
+    assert!(after.peak_bytes >= after.bytes_in_use);
+    drop(a);
+    assert_eq!(allocator::stats().bytes_in_use, before.bytes_in_use);
+}
+
+#[test_case]
+fn leak_tracking_records_live_allocations() {
+    use blog_os::allocator;
+
+    allocator::set_leak_tracking(true);
+    let a = Box::new(1u64);
+    let b = Box::new(2u64);
+    assert_eq!(allocator::live_allocations(), 2);
+    drop(a);
+    assert_eq!(allocator::live_allocations(), 1);
+    allocator::dump_live_allocations();
+    drop(b);
+    assert_eq!(allocator::live_allocations(), 0);
+    allocator::set_leak_tracking(false);
+}
+
+#[test_case]
+fn leak_tracking_forgets_freed_untracked_allocations() {
+    use blog_os::allocator;
+
+    // more allocations than leak tracking records
+    let mut boxes = Vec::with_capacity(600);
+    allocator::set_leak_tracking(true);
+    boxes.extend((0..600u64).map(Box::new));
+    assert_eq!(allocator::live_allocations(), 600);
+    boxes.clear();
+    assert_eq!(allocator::live_allocations(), 0);
+    allocator::set_leak_tracking(false);
+}
+
+#[test_case]
+fn try_alloc_reports_exhaustion() {
+    use blog_os::allocator::{self, try_box, try_vec_with_capacity, TryClone, HEAP_MAX_SIZE};
+
+    let failed = allocator::stats().failed_allocations;
+    let err = try_vec_with_capacity::<u8>(2 * HEAP_MAX_SIZE).unwrap_err();
+    assert_eq!(err.layout.size(), 2 * HEAP_MAX_SIZE);
+    assert_eq!(allocator::stats().failed_allocations, failed + 1);
+
+    let v = try_vec_with_capacity::<u32>(16).unwrap();
+    assert_eq!(v.capacity(), 16);
+    let b = try_box([7u64; 4]).unwrap();
+    assert_eq!(b.try_clone().unwrap(), b);
+}
+
+#[test_case]
+fn try_spawn_reports_exhaustion() {
+    use blog_os::allocator::{self, heap_size, try_vec_with_capacity, HEAP_MAX_SIZE};
+    use blog_os::task::executor::{Executor, SpawnError};
+
+    let mut executor = Executor::try_new().unwrap();
+    let mut blocks: Vec<Vec<u8>> = Vec::with_capacity(64 * 1024);
+    // keep the heap from growing and fill it up, down to the smallest blocks
+    allocator::set_heap_limit(heap_size());
+    for size in [1024, 64, 8, 1] {
+        while blocks.len() < blocks.capacity() {
+            match try_vec_with_capacity(size) {
+                Ok(block) => blocks.push(block),
+                Err(_) => break,
+            }
+        }
+    }
+    assert!(blocks.len() < blocks.capacity());
+    assert!(matches!(
+        executor.try_spawn(async {}),
+        Err(SpawnError::OutOfMemory(_))
+    ));
+
+    drop(blocks);
+    allocator::set_heap_limit(HEAP_MAX_SIZE);
+    assert_eq!(executor.try_spawn(async {}), Ok(()));
+}
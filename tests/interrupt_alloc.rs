@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(abi_x86_interrupt)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use blog_os::{
+    allocator,
+    interrupts::{PICS, PIC_1_OFFSET},
+    memory::{self, BootInfoFrameAllocator},
+};
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    VirtAddr,
+};
+
+/// Number of timer interrupts that allocated successfully
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt[PIC_1_OFFSET].set_handler_fn(allocating_timer_handler);
+        idt
+    };
+}
+
+/// Timer handler that allocates, to catch deadlocks with the interrupted
+/// code holding the allocator lock
+extern "x86-interrupt" fn allocating_timer_handler(_stack_frame: InterruptStackFrame) {
+    let values: Vec<usize> = (0..16).collect();
+    let boxed = Box::new(values.iter().sum::<usize>());
+    assert_eq!(*boxed, 120);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    unsafe {
+        allocator::init_heap(&mut mapper, &mut frame_allocator).unwrap();
+    }
+    memory::install_kernel_paging(mapper, frame_allocator);
+    TEST_IDT.load();
+
+    test_main();
+
+    loop {}
+}
+
+#[test_case]
+fn allocate_in_timer_interrupt() {
+    let start = TICKS.load(Ordering::Relaxed);
+    let mut rounds = 0;
+    while TICKS.load(Ordering::Relaxed) < start + 5 {
+        let v: Vec<Box<usize>> = (0..64).map(Box::new).collect();
+        assert_eq!(*v[63], 63);
+        rounds += 1;
+    }
+    assert!(rounds > 0);
+}
@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use blog_os::{
+    exit_qemu,
+    memory::{self, BootInfoFrameAllocator},
+    serial_print, serial_println, QemuExitCode,
+};
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+/// Set once executing the heap faulted as expected
+static HEAP_EXEC_FAULTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    // the test IDT has no handlers for hardware interrupts
+    x86_64::instructions::interrupts::disable();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    unsafe {
+        memory::protect::protect_kernel(&mut mapper);
+        blog_os::allocator::init_heap(&mut mapper, &mut frame_allocator).unwrap();
+    }
+    TEST_IDT.load();
+
+    serial_print!("kernel_protection::heap_is_not_executable...\t");
+    // a single `ret` instruction
+    let code = Box::new([0xc3u8]);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+    assert!(HEAP_EXEC_FAULTED.load(Ordering::SeqCst));
+    serial_println!("[ok]");
+
+    serial_print!("kernel_protection::text_is_read_only...\t");
+    let text = main as *const () as *mut u8;
+    unsafe { text.write_volatile(0x90) };
+    serial_println!("[failed]\n");
+    serial_println!("Error: writing to .text did not fault\n");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let protection_violation = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    if !HEAP_EXEC_FAULTED.load(Ordering::SeqCst) {
+        assert!(protection_violation && error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH));
+        HEAP_EXEC_FAULTED.store(true, Ordering::SeqCst);
+        // emulate the `ret` that could not be executed
+        unsafe {
+            stack_frame.as_mut().update(|frame| {
+                let return_address = frame.stack_pointer.as_ptr::<u64>().read();
+                frame.instruction_pointer = VirtAddr::new(return_address);
+                frame.stack_pointer += 8u64;
+            });
+        }
+    } else {
+        assert!(protection_violation && error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE));
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        loop {}
+    }
+}
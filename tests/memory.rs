@@ -0,0 +1,331 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+use core::panic::PanicInfo;
+
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    unsafe {
+        // write protection is needed for kernel writes to copy-on-write pages
+        memory::protect::protect_kernel(&mut mapper);
+        blog_os::allocator::init_heap(&mut mapper, &mut frame_allocator).unwrap();
+    }
+    memory::install_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[test_case]
+fn lazy_region_is_backed_on_access() {
+    let region = memory::lazy::reserve(64 * 4096, PageTableFlags::WRITABLE).unwrap();
+    let ptr: *mut u64 = (region.start + 10 * 4096u64).as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+        memory::lazy::release(region).unwrap();
+    }
+}
+
+#[test_case]
+fn kernel_stack_has_guard_page() {
+    let stack = memory::stack::alloc_stack(4).unwrap();
+    assert_eq!(stack.end() - stack.start(), 4 * 4096);
+    unsafe {
+        stack.start().as_mut_ptr::<u64>().write_volatile(1);
+        (stack.end() - 8u64).as_mut_ptr::<u64>().write_volatile(2);
+    }
+    assert_eq!(
+        memory::stack::guard_page_hit(stack.start() - 8u64),
+        Some(stack.id())
+    );
+    assert_eq!(memory::stack::guard_page_hit(stack.start()), None);
+}
+
+#[test_case]
+fn double_fault_stack_has_guard_page() {
+    let stack = blog_os::gdt::init_double_fault_stack().unwrap();
+    assert_eq!(
+        memory::stack::guard_page_hit(stack.start() - 8u64),
+        Some(stack.id())
+    );
+    // later calls keep the stack
+    let again = blog_os::gdt::init_double_fault_stack().unwrap();
+    assert_eq!(again.id(), stack.id());
+}
+
+#[test_case]
+fn translate_reports_mapping() {
+    use blog_os::allocator::HEAP_START;
+    use memory::{MappedSize, TranslateResult};
+
+    match memory::translate(VirtAddr::new(HEAP_START as u64 + 0x123)) {
+        TranslateResult::Mapped { phys, size, flags } => {
+            assert_eq!(phys.as_u64() & 0xfff, 0x123);
+            assert_eq!(size, MappedSize::Page4KiB);
+            assert!(flags.contains(PageTableFlags::WRITABLE));
+        }
+        TranslateResult::NotMapped => panic!("heap start is not mapped"),
+    }
+    let vga = memory::physical_memory_offset() + 0xb8000u64;
+    assert_eq!(
+        memory::translate(vga).phys_addr().map(|p| p.as_u64()),
+        Some(0xb8000)
+    );
+    assert_eq!(
+        memory::translate(VirtAddr::new(0)),
+        TranslateResult::NotMapped
+    );
+}
+
+#[test_case]
+fn snapshot_diff_shows_new_mapping() {
+    use memory::dump::{print_diff, snapshot};
+
+    let region = memory::lazy::reserve(4096, PageTableFlags::WRITABLE).unwrap();
+    let before = snapshot().unwrap();
+    unsafe { region.start.as_mut_ptr::<u8>().write_volatile(1) };
+    let after = snapshot().unwrap();
+    print_diff(&before, &after);
+
+    let covers =
+        |r: &memory::dump::MappingRange| r.virt <= region.start && region.start < r.virt + r.len;
+    assert!(!before.ranges.iter().any(covers));
+    assert!(after.ranges.iter().any(covers));
+    unsafe { memory::lazy::release(region).unwrap() };
+}
+
+#[test_case]
+fn stats_track_frame_usage() {
+    let before = memory::stats().unwrap();
+    assert!(before.usable > 0);
+    assert!(before.kernel > 0);
+    let stack = memory::stack::alloc_stack(8).unwrap();
+    let during = memory::stats().unwrap();
+    assert!(during.frames_allocated >= before.frames_allocated + 8);
+    assert_eq!(
+        during.frames_allocated + during.frames_free,
+        before.frames_allocated + before.frames_free
+    );
+    drop(stack);
+    assert!(memory::stats().unwrap().frames_allocated < during.frames_allocated);
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    memory::with_kernel_paging(|_, frame_allocator| {
+        let free = frame_allocator.free_frames();
+        let frame = frame_allocator.allocate_frame().unwrap();
+        assert_eq!(frame_allocator.free_frames(), free - 1);
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        assert_eq!(frame_allocator.free_frames(), free);
+        assert_eq!(frame_allocator.allocate_frame(), Some(frame));
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn unmap_range_returns_frames_and_tables() {
+    let before = memory::stats().unwrap().frames_allocated;
+    let region = memory::lazy::reserve(64 * 4096, PageTableFlags::WRITABLE).unwrap();
+    for page in region.pages() {
+        unsafe { page.start_address().as_mut_ptr::<u8>().write_volatile(1) };
+    }
+    assert!(memory::stats().unwrap().frames_allocated > before + 64);
+
+    let first = region.pages().next().unwrap();
+    unsafe { memory::remap_range(region.pages(), PageTableFlags::PRESENT).unwrap() };
+    match memory::translate(first.start_address()) {
+        memory::TranslateResult::Mapped { flags, .. } => {
+            assert!(!flags.contains(PageTableFlags::WRITABLE))
+        }
+        memory::TranslateResult::NotMapped => panic!("remap unmapped the page"),
+    }
+
+    unsafe { memory::lazy::release(region).unwrap() };
+    assert_eq!(
+        memory::translate(first.start_address()),
+        memory::TranslateResult::NotMapped
+    );
+    assert_eq!(memory::stats().unwrap().frames_allocated, before);
+}
+
+#[test_case]
+fn address_space_owns_user_mappings() {
+    use memory::address_space::{self, AddressSpace, AddressSpaceError, USER_START};
+    use x86_64::structures::paging::Page;
+
+    // the first address space also sets up the shared kernel tables
+    drop(AddressSpace::new().unwrap());
+    let before = memory::stats().unwrap().frames_allocated;
+
+    let mut space = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(USER_START));
+    space.map(page, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(
+        space.map(page, PageTableFlags::WRITABLE),
+        Err(AddressSpaceError::AlreadyMapped)
+    );
+    let (_, flags) = space.translate(page.start_address()).unwrap();
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE));
+    // user pages are not visible in the kernel page table
+    assert_eq!(
+        memory::translate(page.start_address()),
+        memory::TranslateResult::NotMapped
+    );
+
+    unsafe {
+        space.activate();
+        page.start_address().as_mut_ptr::<u64>().write_volatile(7);
+        assert_eq!(page.start_address().as_ptr::<u64>().read_volatile(), 7);
+    }
+    address_space::activate_kernel();
+    drop(space);
+    assert_eq!(memory::stats().unwrap().frames_allocated, before);
+}
+
+#[test_case]
+fn address_space_pcid_is_reused_after_drop() {
+    use memory::address_space::{self, AddressSpace};
+
+    let space = AddressSpace::new().unwrap();
+    let pcid = space.pcid().map(|p| p.value());
+    unsafe { space.activate() };
+    address_space::activate_kernel();
+    drop(space);
+    let space = AddressSpace::new().unwrap();
+    assert_eq!(space.pcid().map(|p| p.value()), pcid);
+    // the reused PCID is flushed, and kept on the second activation
+    unsafe {
+        space.activate();
+        space.activate();
+    }
+    address_space::activate_kernel();
+}
+
+#[test_case]
+fn clone_cow_copies_on_write() {
+    use memory::address_space::{self, AddressSpace, USER_START};
+    use x86_64::structures::paging::Page;
+
+    // sets up the shared kernel tables and the share counts
+    drop(AddressSpace::new().unwrap().clone_cow().unwrap());
+    let before = memory::stats().unwrap().frames_allocated;
+
+    let mut parent = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(USER_START));
+    let ptr = page.start_address().as_mut_ptr::<u64>();
+    parent.map(page, PageTableFlags::WRITABLE).unwrap();
+    unsafe {
+        parent.activate();
+        ptr.write_volatile(1);
+    }
+    address_space::activate_kernel();
+
+    let child = parent.clone_cow().unwrap();
+    let (parent_frame, parent_flags) = parent.translate(page.start_address()).unwrap();
+    let (child_frame, child_flags) = child.translate(page.start_address()).unwrap();
+    assert_eq!(parent_frame, child_frame);
+    assert!(!parent_flags.contains(PageTableFlags::WRITABLE));
+    assert!(!child_flags.contains(PageTableFlags::WRITABLE));
+
+    // the first write copies the frame
+    unsafe {
+        child.activate();
+        assert_eq!(ptr.read_volatile(), 1);
+        ptr.write_volatile(2);
+        assert_eq!(ptr.read_volatile(), 2);
+    }
+    let (child_frame, child_flags) = child.translate(page.start_address()).unwrap();
+    assert_ne!(child_frame, parent_frame);
+    assert!(child_flags.contains(PageTableFlags::WRITABLE));
+
+    // the parent is the only owner left and keeps its frame
+    unsafe {
+        parent.activate();
+        assert_eq!(ptr.read_volatile(), 1);
+        ptr.write_volatile(3);
+    }
+    let (frame, flags) = parent.translate(page.start_address()).unwrap();
+    assert_eq!(frame, parent_frame);
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+
+    address_space::activate_kernel();
+    drop(child);
+    drop(parent);
+    assert_eq!(memory::stats().unwrap().frames_allocated, before);
+}
+
+#[test_case]
+fn mmio_region_maps_device_memory() {
+    use memory::{vmm, CacheMode};
+    use x86_64::PhysAddr;
+
+    // the VGA text buffer, also reachable through the physical memory window
+    let vga = (memory::physical_memory_offset() + 0xb8000u64).as_ptr::<u16>();
+    let mmio = memory::map_mmio(PhysAddr::new(0xb8002), 4000, CacheMode::Uncached).unwrap();
+    assert_eq!(mmio.base().as_u64() % 4096, 2);
+    let old = mmio.read::<u16>(0);
+    mmio.write::<u16>(0, 0x0f21);
+    assert_eq!(unsafe { vga.add(1).read_volatile() }, 0x0f21);
+    mmio.write::<u16>(0, old);
+
+    match memory::translate(mmio.base()) {
+        memory::TranslateResult::Mapped { phys, flags, .. } => {
+            assert_eq!(phys.as_u64(), 0xb8002);
+            assert!(flags.contains(PageTableFlags::NO_CACHE));
+        }
+        memory::TranslateResult::NotMapped => panic!("mmio region not mapped"),
+    }
+
+    let base = mmio.base();
+    drop(mmio);
+    assert!(vmm::find(base).is_none());
+    assert_eq!(memory::translate(base), memory::TranslateResult::NotMapped);
+}
+
+#[test_case]
+fn write_combining_mmio_region_unmaps_on_drop() {
+    use memory::{vmm, CacheMode};
+    use x86_64::PhysAddr;
+
+    let mmio = memory::map_mmio(PhysAddr::new(0xb8000), 8192, CacheMode::WriteCombining).unwrap();
+    let old = mmio.read::<u16>(0);
+    mmio.write::<u16>(0, old);
+
+    let base = mmio.base();
+    drop(mmio);
+    assert!(vmm::find(base).is_none());
+    assert_eq!(memory::translate(base), memory::TranslateResult::NotMapped);
+    assert_eq!(
+        memory::translate(base + 4096u64),
+        memory::TranslateResult::NotMapped
+    );
+}